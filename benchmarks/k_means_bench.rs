@@ -2,7 +2,11 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 use rand::{self, Rng};
 
-use poker_abstraction::{histogram::*, k_means::k_means};
+use poker_abstraction::{
+    histogram::*,
+    k_means::{generate_centers, k_means},
+    matrix::Matrix,
+};
 
 fn generate_histograms(n: usize, m: usize) -> Vec<Histogram> {
     let mut rng = rand::thread_rng();
@@ -18,19 +22,67 @@ fn generate_histograms(n: usize, m: usize) -> Vec<Histogram> {
         .collect::<Vec<Histogram>>()
 }
 
+fn generate_matrix(n: usize, m: usize) -> Matrix {
+    Matrix::from(&generate_histograms(n, m)[..])
+}
+
+struct Reference {
+    n: usize,
+    s: f32,
+    x: Vec<f32>,
+}
+
+impl Reference {
+    fn from(h: &Histogram) -> Self {
+        Self {
+            n: h.n(),
+            s: h.s(),
+            x: h.x.clone(),
+        }
+    }
+
+    fn get(&self, i: usize) -> f32 {
+        if self.s == 0.0 {
+            0.0
+        } else {
+            self.x[i]
+        }
+    }
+}
+
+fn reference_emd(a: &Reference, b: &Reference) -> f32 {
+    let mut d = 0.0;
+    let mut s = 0.0;
+    for i in 0..std::cmp::min(a.n, b.n) {
+        s += a.get(i) - b.get(i);
+        d += f32::abs(s);
+    }
+
+    d
+}
+
+fn reference_mse(a: &Reference, b: &Reference) -> f32 {
+    let mut d = 0.0;
+    for i in 0..std::cmp::min(a.n, b.n) {
+        let delta = a.get(i) - b.get(i);
+        d += delta * delta;
+    }
+
+    d
+}
+
 fn bench_1k(c: &mut Criterion) {
     let mut g = c.benchmark_group("K-Means 1k");
 
     g.bench_function("K-Means: 1k Histograms (MSE)", |b| {
         b.iter_batched(
-            || generate_histograms(1000, 10),
+            || generate_matrix(1000, 10),
             |input| {
                 k_means(
                     black_box(10),
                     black_box(1),
                     black_box(&input),
-                    black_box(agg),
-                    black_box(mse),
+                    black_box(Metric::Mse),
                 )
             },
             criterion::BatchSize::NumBatches(3),
@@ -39,14 +91,13 @@ fn bench_1k(c: &mut Criterion) {
 
     g.bench_function("K-Means: 1k Histograms (EMD)", |b| {
         b.iter_batched(
-            || generate_histograms(1000, 100),
+            || generate_matrix(1000, 100),
             |input| {
                 k_means(
                     black_box(10),
                     black_box(1),
                     black_box(&input),
-                    black_box(agg),
-                    black_box(emd),
+                    black_box(Metric::Emd),
                 )
             },
             criterion::BatchSize::NumBatches(3),
@@ -63,14 +114,13 @@ fn bench_10k(c: &mut Criterion) {
 
     g.bench_function("K-Means: 10k Histograms (MSE)", |b| {
         b.iter_batched(
-            || generate_histograms(10000, 10),
+            || generate_matrix(10000, 10),
             |input| {
                 k_means(
                     black_box(100),
                     black_box(1),
                     black_box(&input),
-                    black_box(agg),
-                    black_box(mse),
+                    black_box(Metric::Mse),
                 )
             },
             criterion::BatchSize::NumBatches(3),
@@ -79,14 +129,13 @@ fn bench_10k(c: &mut Criterion) {
 
     g.bench_function("K-Means: 10k Histograms (EMD)", |b| {
         b.iter_batched(
-            || generate_histograms(10000, 100),
+            || generate_matrix(10000, 100),
             |input| {
                 k_means(
                     black_box(100),
                     black_box(1),
                     black_box(&input),
-                    black_box(agg),
-                    black_box(emd),
+                    black_box(Metric::Emd),
                 )
             },
             criterion::BatchSize::NumBatches(3),
@@ -97,36 +146,128 @@ fn bench_10k(c: &mut Criterion) {
 }
 
 fn bench_100k(_c: &mut Criterion) {
-    let mse_input = generate_histograms(100000, 10);
+    let mse_input = generate_matrix(100000, 10);
     let mse_start = std::time::Instant::now();
 
     k_means(
         black_box(1000),
         black_box(1),
         black_box(&mse_input),
-        black_box(agg),
-        black_box(mse),
+        black_box(Metric::Mse),
     );
 
     println!("100k Histograms (MSE): {:?}", mse_start.elapsed());
 
-    let emd_input = generate_histograms(100000, 100).clone();
+    let emd_input = generate_matrix(100000, 100);
     let emd_start = std::time::Instant::now();
 
     k_means(
         black_box(1000),
         black_box(1),
         black_box(&emd_input),
-        black_box(agg),
-        black_box(emd),
+        black_box(Metric::Emd),
     );
 
     println!("100k Histograms (EMD): {:?}", emd_start.elapsed());
 }
 
+fn bench_distances(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Distances");
+
+    let histograms = generate_histograms(10000, 47);
+    let matrix = Matrix::from(&histograms[..]);
+    let center = generate_histograms(1, 47).pop().unwrap();
+
+    let reference = histograms.iter().map(Reference::from).collect::<Vec<_>>();
+    let target = Reference::from(&center);
+
+    g.bench_function("EMD: 10k Histograms (reference)", |b| {
+        b.iter(|| {
+            reference
+                .iter()
+                .map(|h| reference_emd(black_box(h), &target))
+                .sum::<f32>()
+        })
+    });
+
+    g.bench_function("EMD: 10k Histograms (matrix kernel)", |b| {
+        b.iter(|| {
+            matrix
+                .rows()
+                .map(|h| Metric::Emd.kernel(black_box(h), &center.x))
+                .sum::<f32>()
+        })
+    });
+
+    g.bench_function("MSE: 10k Histograms (reference)", |b| {
+        b.iter(|| {
+            reference
+                .iter()
+                .map(|h| reference_mse(black_box(h), &target))
+                .sum::<f32>()
+        })
+    });
+
+    g.bench_function("MSE: 10k Histograms (matrix kernel)", |b| {
+        b.iter(|| {
+            matrix
+                .rows()
+                .map(|h| Metric::Mse.kernel(black_box(h), &center.x))
+                .sum::<f32>()
+        })
+    });
+
+    let cumulative = matrix.cumulative::<f32>().unwrap();
+    let target = center.clone().cumulative();
+
+    g.bench_function("EMD: 10k Histograms (cumulative L1 kernel)", |b| {
+        b.iter(|| {
+            cumulative
+                .rows()
+                .map(|h| Metric::L1.kernel(black_box(h), &target.x))
+                .sum::<f32>()
+        })
+    });
+
+    let mut compact = Matrix::<u8>::new(0, matrix.m);
+    for h in matrix.rows() {
+        compact.push(&h.iter().map(|&x| x * 255.0).collect::<Vec<_>>());
+    }
+    let target = center.x.iter().map(|&x| x * 255.0).collect::<Vec<_>>();
+
+    g.bench_function("EMD: 10k Histograms (u8 matrix kernel)", |b| {
+        b.iter(|| {
+            compact
+                .rows()
+                .map(|h| Metric::Emd.kernel(black_box(h), &target))
+                .sum::<f32>()
+        })
+    });
+
+    g.finish();
+}
+
 fn bench_generate_centers(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Centers");
+
+    g.significance_level(0.1).sample_size(10);
+
+    let input = generate_matrix(10000, 47);
 
+    g.bench_function("Centers: 100 from 10k Histograms (EMD)", |b| {
+        b.iter(|| {
+            generate_centers(
+                black_box(100),
+                black_box(&input),
+                black_box(Metric::Emd),
+                &mut rand::thread_rng(),
+            )
+        })
+    });
+
+    g.finish();
 }
 
-criterion_group!(benches, bench_100k);
-criterion_main!(benches);
+criterion_group!(benches, bench_1k, bench_10k, bench_100k);
+criterion_group!(kernels, bench_distances, bench_generate_centers);
+criterion_main!(benches, kernels);
@@ -0,0 +1,45 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use poker_abstraction::{deck::Deck, evaluator::Reference, tables::*};
+
+fn bench_stages(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Tables");
+
+    g.significance_level(0.1).sample_size(10);
+
+    let deck = Deck::new(6, 3).unwrap();
+    let evaluator = Reference::new(deck);
+
+    g.bench_function("Strengths: 6x3 Deck", |b| {
+        b.iter(|| build_strengths(black_box(&deck), black_box(&evaluator)))
+    });
+
+    let strength = build_strengths(&deck, &evaluator);
+
+    g.bench_function("Flop Histograms: 6x3 Deck", |b| {
+        b.iter(|| generate_flop_histograms(black_box(&deck), black_box(&strength)))
+    });
+
+    g.bench_function("Turn Histograms: 6x3 Deck", |b| {
+        b.iter(|| generate_turn_histograms(black_box(&deck), black_box(&strength)))
+    });
+
+    g.bench_function("OCHS Histograms: 6x3 Deck", |b| {
+        b.iter(|| build_ochs_histograms(black_box(&deck), black_box(&strength)))
+    });
+
+    let ochs: Vec<usize> = (0..deck.indexer(vec![2]).count(0) as usize)
+        .map(|i| i % 8)
+        .collect();
+
+    g.bench_function("River Histograms: 6x3 Deck", |b| {
+        b.iter(|| {
+            generate_river_histograms(black_box(&deck), black_box(&evaluator), black_box(&ochs))
+        })
+    });
+
+    g.finish();
+}
+
+criterion_group!(stages, bench_stages);
+criterion_main!(stages);
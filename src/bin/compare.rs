@@ -0,0 +1,23 @@
+use poker_abstraction::{
+    compare::compare, deck::Deck, error::Result, inspect::Street, mapped::Table,
+    metrics::class_weights,
+};
+
+pub fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!("usage: compare <flop|turn|river> <a.tbl> <b.tbl>");
+        return Ok(());
+    }
+
+    let street: Street = args[1].parse()?;
+
+    let a = Table::open(&args[2])?;
+    let b = Table::open(&args[3])?;
+
+    let indexer = street.indexer(&Deck::STANDARD);
+
+    compare(&a, &b, class_weights(&indexer, 1))?.print(20);
+
+    Ok(())
+}
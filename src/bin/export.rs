@@ -0,0 +1,36 @@
+use poker_abstraction::{
+    deck::Deck,
+    error::Result,
+    export::{export, Artifact},
+    inspect::Street,
+    pipeline::Pipeline,
+};
+
+pub fn main() -> Result<()> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        std::env::args().skip(1).partition(|x| x.starts_with("--"));
+    if args.len() < 3 {
+        println!(
+            "usage: export <table|histograms|centroids|transitions> <flop|turn|river> \
+             <output.csv|output.json|output.npy> [deck] [--cards]"
+        );
+        return Ok(());
+    }
+
+    let artifact: Artifact = args[0].parse()?;
+    let street: Street = args[1].parse()?;
+    let deck: Deck = match args.get(3) {
+        Some(x) => x.parse()?,
+        None => Deck::STANDARD,
+    };
+    let cards = flags.iter().any(|x| x == "--cards");
+
+    let pipeline = Pipeline::new(
+        "data/evaluator".to_string(),
+        "data/histograms/".to_string(),
+        "data/tables/".to_string(),
+    )?
+    .with_deck(deck)?;
+
+    export(&pipeline, artifact, street, &args[2], cards)
+}
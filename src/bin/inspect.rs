@@ -0,0 +1,35 @@
+use poker_abstraction::{
+    error::{Error, Result},
+    inspect::{bucket, inspect, Street},
+    pipeline::Pipeline,
+};
+
+
+pub fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        println!("usage: inspect <flop|turn|river> <bucket|hole/board> [samples]");
+        return Ok(());
+    }
+
+    let street: Street = args[1].parse()?;
+    let samples: usize = match args.get(3) {
+        Some(x) => x.parse().map_err(|_| Error::Parse(x.clone()))?,
+        None => 10,
+    };
+
+    let pipeline = Pipeline::new(
+        "data/evaluator".to_string(),
+        "data/histograms/".to_string(),
+        "data/tables/".to_string(),
+    )?;
+
+    let id: u16 = match args[2].parse() {
+        Ok(id) => id,
+        Err(_) => bucket(&pipeline, street, &args[2])?,
+    };
+
+    inspect(&pipeline, street, id, samples)?.print();
+
+    Ok(())
+}
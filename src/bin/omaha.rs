@@ -0,0 +1,51 @@
+use poker_abstraction::{
+    deck::Deck,
+    error::{Error, Result},
+    inspect::Street,
+    pipeline::Pipeline,
+    sampled::Sampling,
+};
+
+fn arg(args: &[String], i: usize, default: usize) -> Result<usize> {
+    match args.get(i) {
+        Some(x) => x.parse().map_err(|_| Error::Parse(x.clone())),
+        None => Ok(default),
+    }
+}
+
+pub fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        println!("usage: omaha <flop|turn|river> <k> [opponents] [runouts] [deck]");
+        return Ok(());
+    }
+
+    let street: Street = args[1].parse()?;
+    let k = arg(&args, 2, 0)?;
+
+    let defaults = Sampling::default();
+    let sampling = Sampling {
+        opponents: arg(&args, 3, defaults.opponents)?,
+        runouts: arg(&args, 4, defaults.runouts)?,
+        ..defaults
+    };
+
+    let deck: Deck = match args.get(5) {
+        Some(x) => x.parse()?,
+        None => Deck::STANDARD,
+    };
+
+    let pipeline = Pipeline::new(
+        "data/evaluator".to_string(),
+        "data/histograms/".to_string(),
+        "data/tables/".to_string(),
+    )?
+    .with_deck(deck)?
+    .with_sampling(Some(sampling));
+
+    let table = pipeline.omaha(street, k)?;
+
+    println!("{} {} buckets over {} hands", street.name(), k, table.len());
+
+    Ok(())
+}
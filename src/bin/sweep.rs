@@ -0,0 +1,62 @@
+use rand::thread_rng;
+
+use poker_abstraction::{
+    error::{Error, Result},
+    inspect::Street,
+    metrics::class_weights,
+    pipeline::Pipeline,
+    sweep::{sample, sweep},
+    tables,
+};
+
+fn arg(args: &[String], i: usize, default: usize) -> Result<usize> {
+    match args.get(i) {
+        Some(x) => x.parse().map_err(|_| Error::Parse(x.clone())),
+        None => Ok(default),
+    }
+}
+
+pub fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 5 {
+        println!("usage: sweep <flop|turn|river> <from> <to> <step> [samples] [references]");
+        return Ok(());
+    }
+
+    let street: Street = args[1].parse()?;
+    let ks: Vec<usize> = (arg(&args, 2, 0)?..=arg(&args, 3, 0)?)
+        .step_by(arg(&args, 4, 1)?.max(1))
+        .collect();
+    let samples = arg(&args, 5, 100_000)?;
+    let references = arg(&args, 6, 0)?;
+
+    let pipeline = Pipeline::new(
+        "data/evaluator".to_string(),
+        "data/histograms/".to_string(),
+        "data/tables/".to_string(),
+    )?;
+
+    let (idxs, points) = sample(&pipeline, street, samples, &mut thread_rng())?;
+
+    let indexer = street.indexer(&pipeline.deck);
+    let weight = class_weights(&indexer, 1);
+
+    let result = sweep(
+        &ks,
+        &points,
+        street.metric(),
+        references,
+        |i| weight(idxs[i]),
+        street.equity(),
+    )?;
+
+    println!(
+        "suggested k: elbow = {:?}, gap = {:?}",
+        result.elbow, result.gap
+    );
+
+    tables::write(
+        &(pipeline.tables.clone() + street.name() + ".sweep.json"),
+        |writer| Ok(serde_json::to_writer_pretty(writer, &result)?),
+    )
+}
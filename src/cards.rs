@@ -0,0 +1,99 @@
+use crate::error::{Error, Result};
+
+pub const RANKS: &[u8; 13] = b"23456789TJQKA";
+
+pub const SUITS: &[u8; 4] = b"cdhs";
+
+pub fn card(c: usize) -> String {
+    format!("{}{}", RANKS[c % 13] as char, SUITS[c / 13] as char)
+}
+
+pub fn to_string(mask: u64) -> String {
+    (0..52)
+        .rev()
+        .filter(|&c| mask >> c & 1 == 1)
+        .map(card)
+        .collect()
+}
+
+pub fn parse(s: &str) -> Result<u64> {
+    let bytes = s.as_bytes();
+    if !bytes.len().is_multiple_of(2) {
+        return Err(Error::Parse(s.to_string()));
+    }
+
+    let mut mask = 0;
+    for pair in bytes.chunks(2) {
+        let rank = RANKS
+            .iter()
+            .position(|&r| r == pair[0].to_ascii_uppercase());
+        let suit = SUITS
+            .iter()
+            .position(|&x| x == pair[1].to_ascii_lowercase());
+
+        match (rank, suit) {
+            (Some(r), Some(s)) if mask >> (s * 13 + r) & 1 == 0 => mask |= 1 << (s * 13 + r),
+            _ => return Err(Error::Parse(s.to_string())),
+        }
+    }
+
+    Ok(mask)
+}
+
+fn permute(mask: u64, p: &[u64; 4]) -> u64 {
+    (0..4).fold(0, |x, s| x | (mask >> (13 * s) & 0x1fff) << (13 * p[s]))
+}
+
+// number of raw card combinations that share the suit-isomorphism class of `rounds`
+pub fn class_size(rounds: &[u64]) -> u64 {
+    let mut fixed = 0;
+    for a in 0..4 {
+        for b in 0..4 {
+            for c in 0..4 {
+                if a == b || a == c || b == c {
+                    continue;
+                }
+
+                let p = [a, b, c, 6 - a - b - c];
+                if rounds.iter().all(|&mask| permute(mask, &p) == mask) {
+                    fixed += 1;
+                }
+            }
+        }
+    }
+
+    24 / fixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_card_strings() {
+        let mask = parse("AsKd2c").unwrap();
+
+        assert_eq!(mask, 1 << 51 | 1 << 24 | 1);
+        assert_eq!(to_string(mask), "AsKd2c");
+        assert_eq!(parse("2c").unwrap(), 1);
+
+        assert!(parse("AsAs").is_err());
+        assert!(parse("Xs").is_err());
+        assert!(parse("A").is_err());
+    }
+
+    #[test]
+    fn test_class_size() {
+        assert_eq!(class_size(&[parse("AsKs").unwrap()]), 4);
+        assert_eq!(class_size(&[parse("AsKd").unwrap()]), 12);
+        assert_eq!(class_size(&[parse("AsAd").unwrap()]), 6);
+        assert_eq!(
+            class_size(&[parse("AsKd").unwrap(), parse("2c3h4s").unwrap()]),
+            24
+        );
+        assert_eq!(
+            class_size(&[parse("AsKs").unwrap(), parse("2c3c4c").unwrap()]),
+            12
+        );
+    }
+}
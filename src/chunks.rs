@@ -0,0 +1,218 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+use rand::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{check, Error, Result};
+use crate::matrix::Matrix;
+use crate::tables;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Chunks {
+    pub n: usize,
+    pub size: usize,
+    pub chunk: usize,
+    // the largest feature value, mapped to 255
+    pub max: f32,
+
+    #[serde(skip)]
+    pub path: String,
+}
+
+fn meta(path: &str) -> String {
+    path.to_string() + "meta.bin"
+}
+
+impl Chunks {
+    pub fn exists(path: &str) -> bool {
+        Path::new(&meta(path)).exists()
+    }
+
+    pub fn open(path: &String) -> Result<Self> {
+        println!("Opening chunks in {}", path);
+
+        let mut chunks: Chunks = tables::load(&meta(path)).map_err(|e| match e {
+            Error::Bincode(_) => Error::Corrupt(format!(
+                "{}: unreadable chunk meta, possibly written before the value scale was stored; \
+                 delete the directory to rebuild it",
+                path
+            )),
+            e => e,
+        })?;
+        chunks.path = path.clone();
+
+        Ok(chunks)
+    }
+
+    pub fn quantise(&self, x: f32) -> u8 {
+        (x.clamp(0.0, self.max) / self.max * 255.0).round() as u8
+    }
+
+    pub fn dequantise(&self, x: u8) -> f32 {
+        x as f32 / 255.0 * self.max
+    }
+
+    pub fn count(&self) -> usize {
+        self.n.div_ceil(self.chunk)
+    }
+
+    pub fn range(&self, c: usize) -> Range<usize> {
+        c * self.chunk..std::cmp::min(self.n, (c + 1) * self.chunk)
+    }
+
+    fn file(&self, c: usize) -> String {
+        format!("{}{:05}.bin", self.path, c)
+    }
+
+    pub fn load(&self, c: usize) -> Result<Matrix<u8>> {
+        let mut buffer = Vec::new();
+        File::open(self.file(c))?.read_to_end(&mut buffer)?;
+
+        check(self.range(c).len() * self.size, buffer.len())?;
+
+        Ok(Matrix {
+            n: self.range(c).len(),
+            m: self.size,
+            x: buffer,
+        })
+    }
+
+    pub fn sample<R: Rng>(&self, count: usize, rng: &mut R) -> Result<Matrix<u8>> {
+        let p = (count as f64 / self.n as f64).min(1.0);
+
+        let mut sample = Matrix::new(0, self.size);
+        for c in 0..self.count() {
+            for row in self.load(c)?.rows() {
+                if rng.gen_bool(p) {
+                    sample.push(row);
+                }
+            }
+        }
+
+        Ok(sample)
+    }
+}
+
+pub struct Writer {
+    chunks: Chunks,
+    spills: Vec<BufWriter<File>>,
+}
+
+impl Writer {
+    pub fn new(path: &String, n: usize, size: usize, chunk: usize, max: f32) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+
+        let chunks = Chunks {
+            n,
+            size,
+            chunk,
+            max,
+            path: path.clone(),
+        };
+
+        let spills = (0..chunks.count())
+            .map(|c| Ok(BufWriter::new(File::create(chunks.file(c) + ".spill")?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { chunks, spills })
+    }
+
+    pub fn push(&mut self, index: usize, x: &[f32]) -> Result<()> {
+        check(self.chunks.size, x.len())?;
+
+        let spill = &mut self.spills[index / self.chunks.chunk];
+
+        spill.write_all(&(index as u64).to_le_bytes())?;
+        let row: Vec<u8> = x.iter().map(|&x| self.chunks.quantise(x)).collect();
+        spill.write_all(&row)?;
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Chunks> {
+        let chunks = self.chunks;
+
+        for (c, spill) in self.spills.into_iter().enumerate() {
+            drop(spill.into_inner().map_err(|e| e.into_error())?);
+
+            let path = chunks.file(c) + ".spill";
+
+            let mut buffer = Vec::new();
+            File::open(&path)?.read_to_end(&mut buffer)?;
+
+            let range = chunks.range(c);
+
+            let mut data = vec![0; range.len() * chunks.size];
+            for record in buffer.chunks(8 + chunks.size) {
+                let index = u64::from_le_bytes(record[..8].try_into().unwrap()) as usize;
+                let i = (index - range.start) * chunks.size;
+
+                data[i..i + chunks.size].copy_from_slice(&record[8..]);
+            }
+
+            tables::write(&chunks.file(c), |writer| Ok(writer.write_all(&data)?))?;
+
+            std::fs::remove_file(&path)?;
+        }
+
+        tables::save(&meta(&chunks.path), &chunks)?;
+
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::histogram::Metric;
+    use crate::k_means::k_means_chunked;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_chunks_round_trip() {
+        let path = temp_dir("chunks");
+
+        let points = [
+            [0.2, 0.4, 0.6],
+            [0.2, 0.6, 0.6],
+            [0.2, 1.8, 0.2],
+            [0.6, 1.8, 0.4],
+            [1.8, 1.4, 0.4],
+            [1.2, 1.4, 0.2],
+            [1.8, 1.6, 0.4],
+        ];
+
+        let mut writer = Writer::new(&path, points.len(), 3, 3, 2.0).unwrap();
+        for i in (0..points.len()).rev() {
+            writer.push(i, &points[i]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let chunks = Chunks::open(&path).unwrap();
+        assert_eq!(chunks.count(), 3);
+
+        let loaded: Vec<u8> = (0..chunks.count())
+            .flat_map(|c| chunks.load(c).unwrap().x)
+            .collect();
+        for (h, x) in loaded.chunks(3).zip(points) {
+            for (&a, b) in h.iter().zip(x) {
+                assert!((chunks.dequantise(a) - b).abs() <= chunks.max / 510.0 + 1e-6);
+            }
+        }
+
+        let actual = k_means_chunked(3, 5, &chunks, Metric::Mse).unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert!(actual[0] == actual[1]);
+        assert!(actual[2] == actual[3]);
+        assert!(actual[4] == actual[5] && actual[5] == actual[6]);
+    }
+}
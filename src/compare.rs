@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{check, Result};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Overlap {
+    pub cluster: usize,
+    pub weight: f64,
+    pub best: usize,
+    pub fraction: f64,
+    pub spread: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Comparison {
+    pub ari: f64,
+    pub nmi: f64,
+    pub vi: f64,
+    pub purity: f64,
+    pub overlaps: Vec<Overlap>,
+}
+
+impl Comparison {
+    pub fn print(&self, top: usize) {
+        println!(
+            "ari = {:.4}, nmi = {:.4}, vi = {:.4}, purity = {:.4}",
+            self.ari, self.nmi, self.vi, self.purity
+        );
+
+        let mut worst = self.overlaps.clone();
+        worst.sort_by(|a, b| a.fraction.total_cmp(&b.fraction));
+        for o in worst.iter().take(top) {
+            println!(
+                "  cluster {}: {:.1}% in {} (spread over {} clusters, weight {})",
+                o.cluster,
+                100.0 * o.fraction,
+                o.best,
+                o.spread,
+                o.weight
+            );
+        }
+    }
+}
+
+fn pairs(x: f64) -> f64 {
+    x * (x - 1.0) / 2.0
+}
+
+fn entropy(sizes: &[f64], n: f64) -> f64 {
+    -sizes
+        .iter()
+        .filter(|&&x| x > 0.0)
+        .map(|&x| x / n * (x / n).ln())
+        .sum::<f64>()
+}
+
+pub fn compare<A: Copy + Into<usize>, B: Copy + Into<usize>>(
+    a: &[A],
+    b: &[B],
+    weight: impl Fn(usize) -> f64,
+) -> Result<Comparison> {
+    check(a.len(), b.len())?;
+
+    let ka = a.iter().map(|&x| x.into() + 1).max().unwrap_or(0);
+    let kb = b.iter().map(|&x| x.into() + 1).max().unwrap_or(0);
+
+    let mut table: HashMap<(usize, usize), f64> = HashMap::new();
+    let mut rows = vec![0.0; ka];
+    let mut cols = vec![0.0; kb];
+    for (i, (&x, &y)) in a.iter().zip(b).enumerate() {
+        let w = weight(i);
+        let (x, y) = (x.into(), y.into());
+
+        *table.entry((x, y)).or_default() += w;
+        rows[x] += w;
+        cols[y] += w;
+    }
+
+    let n: f64 = rows.iter().sum();
+
+    let index: f64 = table.values().map(|&x| pairs(x)).sum();
+    let sum_a: f64 = rows.iter().map(|&x| pairs(x)).sum();
+    let sum_b: f64 = cols.iter().map(|&x| pairs(x)).sum();
+    let expected = sum_a * sum_b / pairs(n).max(f64::MIN_POSITIVE);
+    let max = (sum_a + sum_b) / 2.0;
+    let ari = if max == expected {
+        1.0
+    } else {
+        (index - expected) / (max - expected)
+    };
+
+    let mutual: f64 = table
+        .iter()
+        .filter(|(_, &x)| x > 0.0)
+        .map(|(&(i, j), &x)| x / n * (x * n / (rows[i] * cols[j])).ln())
+        .sum();
+
+    let (ha, hb) = (entropy(&rows, n), entropy(&cols, n));
+    let nmi = if ha + hb > 0.0 {
+        2.0 * mutual / (ha + hb)
+    } else {
+        1.0
+    };
+    let vi = (ha + hb - 2.0 * mutual).max(0.0);
+
+    let mut overlaps: Vec<Overlap> = (0..ka)
+        .map(|cluster| Overlap {
+            cluster,
+            weight: rows[cluster],
+            best: 0,
+            fraction: 0.0,
+            spread: 0,
+        })
+        .collect();
+    for (&(i, j), &x) in &table {
+        let o = &mut overlaps[i];
+        o.spread += 1;
+        let fraction = x / rows[i];
+        if fraction > o.fraction || (fraction == o.fraction && j < o.best) {
+            o.fraction = fraction;
+            o.best = j;
+        }
+    }
+    overlaps.retain(|o| o.weight > 0.0);
+
+    let purity =
+        overlaps.iter().map(|o| o.fraction * o.weight).sum::<f64>() / n.max(f64::MIN_POSITIVE);
+
+    Ok(Comparison {
+        ari,
+        nmi,
+        vi,
+        purity,
+        overlaps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_partitions() {
+        let a: Vec<u16> = vec![0, 0, 1, 1, 2, 2];
+        let b: Vec<usize> = vec![2, 2, 0, 0, 1, 1];
+
+        let same = compare(&a, &b, |i| (i + 1) as f64).unwrap();
+        assert!((same.ari - 1.0).abs() < 1e-9);
+        assert!((same.nmi - 1.0).abs() < 1e-9);
+        assert!(same.vi.abs() < 1e-9);
+        assert_eq!(same.purity, 1.0);
+        assert_eq!(same.overlaps[1].best, 0);
+
+        let c: Vec<u16> = vec![0, 1, 0, 1, 0, 1];
+        let other = compare(&a, &c, |_| 1.0).unwrap();
+        assert!(other.ari < 0.0);
+        assert!(other.nmi.abs() < 1e-9);
+        assert!((other.vi - (3.0f64.ln() + 2.0f64.ln())).abs() < 1e-9);
+        assert_eq!(other.overlaps[0].spread, 2);
+        assert_eq!(other.purity, 0.5);
+    }
+}
@@ -0,0 +1,292 @@
+use std::str::FromStr;
+
+use smallvec::SmallVec;
+
+use poker_indexer::Indexer;
+
+use crate::cards::class_size;
+use crate::error::{Error, Result};
+
+pub fn choose(n: usize, k: usize) -> u64 {
+    if k > n {
+        return 0;
+    }
+
+    (0..k as u64).fold(1, |x, i| x * (n as u64 - i) / (i + 1))
+}
+
+// the top `ranks` ranks of the first `suits` suits, keeping the usual suit * 13 + rank card
+// numbering so masks from a small deck are valid masks of the full one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deck {
+    pub ranks: usize,
+    pub suits: usize,
+}
+
+impl Deck {
+    pub const STANDARD: Deck = Deck {
+        ranks: 13,
+        suits: 4,
+    };
+
+    pub const SHORT: Deck = Deck { ranks: 9, suits: 4 };
+
+    pub fn new(ranks: usize, suits: usize) -> Result<Self> {
+        if !(1..=13).contains(&ranks) || !(1..=4).contains(&suits) || ranks * suits < 9 {
+            return Err(Error::Unsupported(format!(
+                "deck of {} ranks and {} suits",
+                ranks, suits
+            )));
+        }
+
+        Ok(Self { ranks, suits })
+    }
+
+    pub fn size(&self) -> usize {
+        self.ranks * self.suits
+    }
+
+    pub fn is_standard(&self) -> bool {
+        *self == Self::STANDARD
+    }
+
+    pub fn low(&self) -> usize {
+        13 - self.ranks
+    }
+
+    pub fn mask(&self) -> u64 {
+        let suit = ((1u64 << self.ranks) - 1) << self.low();
+
+        (0..self.suits).fold(0, |mask, s| mask | suit << (13 * s))
+    }
+
+    pub fn cards(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..52).filter(move |&c| self.mask() >> c & 1 == 1)
+    }
+
+    pub fn hands(&self, n: usize, used: u64) -> Vec<u64> {
+        let cards: Vec<usize> = self.cards().filter(|&c| used >> c & 1 == 0).collect();
+
+        let mut result = Vec::new();
+        let mut stack = vec![(0, 0u64)];
+        while let Some((start, mask)) = stack.pop() {
+            if mask.count_ones() as usize == n {
+                result.push(mask);
+                continue;
+            }
+
+            for (j, &c) in cards.iter().enumerate().skip(start).rev() {
+                stack.push((j + 1, mask | 1 << c));
+            }
+        }
+
+        result
+    }
+
+    // strengths count two per opponent hand beaten and one per tie, over the opponent hands
+    // that share no card with the board or our hole
+    pub fn strength(&self) -> f32 {
+        2.0 * choose(self.size() - 7, 2) as f32
+    }
+
+    // poker_indexer only knows the full deck, so every other deck indexes raw combinations
+    pub fn indexer(&self, rounds: Vec<u8>) -> Indexing {
+        if self.is_standard() {
+            Indexing::Canonical(Indexer::new(rounds))
+        } else {
+            Indexing::Raw(Raw::new(*self, rounds))
+        }
+    }
+}
+
+// "standard", "short" or "{ranks}x{suits}"
+impl FromStr for Deck {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "standard" => Ok(Deck::STANDARD),
+            "short" => Ok(Deck::SHORT),
+            _ => {
+                let parse = |x: &str| x.parse().map_err(|_| Error::Parse(s.to_string()));
+                match s.split_once('x') {
+                    Some((ranks, suits)) => Deck::new(parse(ranks)?, parse(suits)?),
+                    None => Err(Error::Parse(s.to_string())),
+                }
+            }
+        }
+    }
+}
+
+// colex rank of each round among the deck cards not used by earlier rounds, without any
+// suit isomorphism, so every index is a single raw combination
+pub struct Raw {
+    deck: Deck,
+    rounds: Vec<u8>,
+    count: Vec<u64>,
+}
+
+impl Raw {
+    fn new(deck: Deck, rounds: Vec<u8>) -> Self {
+        let mut left = deck.size();
+        let mut total = 1;
+
+        let count = rounds
+            .iter()
+            .map(|&k| {
+                total *= choose(left, k as usize);
+                left -= k as usize;
+                total
+            })
+            .collect();
+
+        Self {
+            deck,
+            rounds,
+            count,
+        }
+    }
+
+    fn index(&self, cards: &[u64]) -> u64 {
+        let mut free = self.deck.mask();
+        let mut left = self.deck.size();
+
+        let mut index = 0;
+        for (&mask, &k) in cards.iter().zip(&self.rounds) {
+            let mut rank = 0;
+            let mut rest = mask;
+            for j in 0..k as usize {
+                let c = rest.trailing_zeros();
+                rest &= rest - 1;
+                rank += choose((free & ((1 << c) - 1)).count_ones() as usize, j + 1);
+            }
+
+            index = index * choose(left, k as usize) + rank;
+            free &= !mask;
+            left -= k as usize;
+        }
+
+        index
+    }
+
+    fn unindex(&self, mut index: u64, round: usize) -> SmallVec<[u64; 4]> {
+        let mut lefts = Vec::new();
+        let mut left = self.deck.size();
+        for &k in &self.rounds[..=round] {
+            lefts.push(left);
+            left -= k as usize;
+        }
+
+        let mut ranks = vec![0; round + 1];
+        for r in (0..=round).rev() {
+            let n = choose(lefts[r], self.rounds[r] as usize);
+            ranks[r] = index % n;
+            index /= n;
+        }
+
+        let mut free = self.deck.mask();
+        let mut result = SmallVec::new();
+        for (mut rank, &k) in ranks.into_iter().zip(&self.rounds) {
+            let mut mask = 0;
+            let mut cards = free;
+            let mut p = free.count_ones() as usize;
+            for j in (1..=k as usize).rev() {
+                loop {
+                    let c = 63 - cards.leading_zeros();
+                    cards &= !(1 << c);
+                    p -= 1;
+
+                    if choose(p, j) <= rank {
+                        rank -= choose(p, j);
+                        mask |= 1 << c;
+                        break;
+                    }
+                }
+            }
+
+            free &= !mask;
+            result.push(mask);
+        }
+
+        result
+    }
+}
+
+pub enum Indexing {
+    Canonical(Indexer),
+    Raw(Raw),
+}
+
+impl Indexing {
+    pub fn count(&self, round: usize) -> u64 {
+        match self {
+            Indexing::Canonical(indexer) => indexer.count[round],
+            Indexing::Raw(raw) => raw.count[round],
+        }
+    }
+
+    pub fn index(&self, cards: &[u64]) -> u64 {
+        match self {
+            Indexing::Canonical(indexer) => indexer.index(cards.iter().copied().collect()),
+            Indexing::Raw(raw) => raw.index(cards),
+        }
+    }
+
+    pub fn unindex(&self, index: u64, round: usize) -> SmallVec<[u64; 4]> {
+        match self {
+            Indexing::Canonical(indexer) => indexer.unindex(index, round),
+            Indexing::Raw(raw) => raw.unindex(index, round),
+        }
+    }
+
+    pub fn class_size(&self, rounds: &[u64]) -> u64 {
+        match self {
+            Indexing::Canonical(_) => class_size(rounds),
+            Indexing::Raw(_) => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_indexing() {
+        let deck = Deck::new(5, 3).unwrap();
+
+        assert_eq!(deck.size(), 15);
+        assert_eq!(deck.hands(2, 0).len(), 105);
+        assert_eq!(deck.hands(5, deck.hands(2, 0)[7]).len(), 1287);
+        assert!(deck.hands(3, 0).iter().all(|&m| m & !deck.mask() == 0));
+        assert!(Deck::new(4, 2).is_err());
+        assert_eq!("5x3".parse::<Deck>().unwrap(), deck);
+        assert_eq!("short".parse::<Deck>().unwrap(), Deck::SHORT);
+        assert!("4x2".parse::<Deck>().is_err() && "5y3".parse::<Deck>().is_err());
+
+        let indexer = deck.indexer(vec![2, 3]);
+        assert_eq!(indexer.count(0), 105);
+        assert_eq!(indexer.count(1), 105 * 286);
+
+        for i in 0..indexer.count(1) {
+            let rounds = indexer.unindex(i, 1);
+
+            assert_eq!(rounds[0].count_ones(), 2);
+            assert_eq!(rounds[1].count_ones(), 3);
+            assert_eq!(rounds[0] & rounds[1], 0);
+            assert_eq!(indexer.index(&rounds), i);
+            assert_eq!(indexer.index(&rounds[..1]), i / 286);
+        }
+
+        let short = Deck::SHORT;
+        assert_eq!(short.size(), 36);
+        assert_eq!(short.cards().next(), Some(4));
+        assert_eq!(short.indexer(vec![2, 5]).count(1), 630 * 278256);
+        assert_eq!(short.strength(), 812.0);
+
+        // the largest strength beats every opponent left once the board and our hole are out
+        let used = short.cards().take(7).fold(0u64, |mask, c| mask | 1 << c);
+        assert_eq!(2.0 * short.hands(2, used).len() as f32, short.strength());
+        assert_eq!(short.hands(2, used).len(), 406);
+    }
+}
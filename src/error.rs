@@ -0,0 +1,79 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    Corrupt(String),
+    MissingDirectory(String),
+    MissingEvaluator(String),
+    DimensionMismatch { expected: usize, actual: usize },
+    InvalidCards(Vec<u64>),
+    Parse(String),
+    Unsupported(String),
+    Poisoned(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Bincode(e) => write!(f, "corrupt data: {}", e),
+            Error::Json(e) => write!(f, "json error: {}", e),
+            Error::Corrupt(reason) => write!(f, "corrupt data: {}", reason),
+            Error::MissingDirectory(path) => write!(f, "missing directory: {}", path),
+            Error::MissingEvaluator(path) => write!(f, "missing evaluator data: {}", path),
+            Error::DimensionMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "dimension mismatch: expected {}, found {}",
+                    expected, actual
+                )
+            }
+            Error::InvalidCards(cards) => write!(f, "invalid card masks: {:?}", cards),
+            Error::Parse(s) => write!(f, "cannot parse: {}", s),
+            Error::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            Error::Poisoned(what) => write!(f, "lock poisoned: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Bincode(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Bincode(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+pub fn check(expected: usize, actual: usize) -> Result<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::DimensionMismatch { expected, actual })
+    }
+}
@@ -0,0 +1,248 @@
+use poker_evaluator::Evaluator;
+
+use crate::deck::Deck;
+
+pub trait HandEvaluator: Send + Sync {
+    fn evaluate(&self, cards: u64) -> u16;
+}
+
+impl HandEvaluator for Evaluator {
+    fn evaluate(&self, cards: u64) -> u16 {
+        Evaluator::evaluate(self, cards)
+    }
+}
+
+const HIGH: u32 = 0;
+const PAIR: u32 = 1;
+const TWO_PAIR: u32 = 2;
+const TRIPS: u32 = 3;
+const STRAIGHT: u32 = 4;
+const FLUSH: u32 = 5;
+const FULL_HOUSE: u32 = 6;
+const QUADS: u32 = 7;
+const STRAIGHT_FLUSH: u32 = 8;
+
+fn top(ranks: u32) -> impl Iterator<Item = u32> {
+    (0..13).rev().filter(move |&r| ranks >> r & 1 == 1)
+}
+
+fn key(category: u32, ranks: impl Iterator<Item = u32>) -> u32 {
+    ranks
+        .chain(std::iter::repeat(0))
+        .take(5)
+        .fold(category, |x, r| x << 4 | r)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ranking {
+    Standard,
+    // short deck: a flush is rarer than a full house so it ranks above it
+    Short,
+}
+
+impl Ranking {
+    pub fn of(deck: &Deck) -> Self {
+        if *deck == Deck::SHORT {
+            Ranking::Short
+        } else {
+            Ranking::Standard
+        }
+    }
+}
+
+// slow but obviously correct evaluator for any deck: the best five-card key found directly
+// from the rank and suit counts, then compressed to its position among all five-card keys
+pub struct Reference {
+    deck: Deck,
+    ranking: Ranking,
+    keys: Vec<u32>,
+}
+
+impl Reference {
+    pub fn new(deck: Deck) -> Self {
+        Self::with_ranking(deck, Ranking::of(&deck))
+    }
+
+    pub fn with_ranking(deck: Deck, ranking: Ranking) -> Self {
+        let mut reference = Self {
+            deck,
+            ranking,
+            keys: Vec::new(),
+        };
+
+        let mut keys: Vec<u32> = deck
+            .hands(5, 0)
+            .into_iter()
+            .map(|hand| reference.key(hand))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        reference.keys = keys;
+        reference
+    }
+
+    // the ace plays low with the deck's four lowest ranks
+    fn straight(&self, ranks: u32) -> Option<u32> {
+        if let Some(high) = (4..13).rev().find(|&h| ranks >> (h - 4) & 0x1f == 0x1f) {
+            return Some(high);
+        }
+
+        let low = self.deck.low() as u32;
+        let wheel = 1 << 12 | 0xf << low;
+        if self.deck.ranks > 5 && ranks & wheel == wheel {
+            Some(low + 3)
+        } else {
+            None
+        }
+    }
+
+    pub fn key(&self, cards: u64) -> u32 {
+        let suits = [0, 1, 2, 3].map(|s| (cards >> (13 * s) & 0x1fff) as u32);
+        let all = suits.iter().fold(0, |x, &s| x | s);
+
+        // bit r of with[n - 1] is set when rank r appears at least n times
+        let mut with = [0; 4];
+        for s in suits {
+            let mut carry = s;
+            for w in with.iter_mut() {
+                let next = *w & carry;
+                *w |= carry;
+                carry = next;
+            }
+        }
+
+        let flush = suits.into_iter().find(|s| s.count_ones() >= 5);
+
+        if let Some(high) = flush.and_then(|s| self.straight(s)) {
+            return key(STRAIGHT_FLUSH, [high].into_iter());
+        }
+
+        if let Some(quads) = top(with[3]).next() {
+            return key(QUADS, [quads].into_iter().chain(top(all & !(1 << quads))));
+        }
+
+        let (flushes, full_houses) = match self.ranking {
+            Ranking::Standard => (FLUSH, FULL_HOUSE),
+            Ranking::Short => (FULL_HOUSE, FLUSH),
+        };
+
+        let full_house = top(with[2]).next().and_then(|trips| {
+            top(with[1] & !(1 << trips))
+                .next()
+                .map(|pair| key(full_houses, [trips, pair].into_iter()))
+        });
+        let flush = flush.map(|s| key(flushes, top(s)));
+
+        if let Some(k) = full_house.max(flush) {
+            return k;
+        }
+
+        if let Some(high) = self.straight(all) {
+            return key(STRAIGHT, [high].into_iter());
+        }
+
+        if let Some(trips) = top(with[2]).next() {
+            return key(TRIPS, [trips].into_iter().chain(top(all & !(1 << trips))));
+        }
+
+        let pairs = top(with[1]).take(2).fold(0, |x, r| x | 1 << r);
+        let ranks = top(pairs).chain(top(all & !pairs));
+        match pairs.count_ones() {
+            2 => key(TWO_PAIR, ranks.take(3)),
+            1 => key(PAIR, ranks),
+            _ => key(HIGH, ranks),
+        }
+    }
+}
+
+impl HandEvaluator for Reference {
+    fn evaluate(&self, cards: u64) -> u16 {
+        let key = self.key(cards);
+
+        self.keys.partition_point(|&k| k < key) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::cards::parse;
+
+    #[test]
+    fn test_reference_ranking() {
+        let reference = Reference::new(Deck::STANDARD);
+        let rank = |s: &str| reference.evaluate(parse(s).unwrap());
+
+        assert_eq!(reference.keys.len(), 7462);
+
+        let ladder = [
+            "7c5d4h3s2c9d8h",
+            "AcKdQhJs8c7d5h",
+            "2c2d7h8s9c4dJh",
+            "2c2d3h3s9c4dJh",
+            "2c2d2h8s9c4dJh",
+            "Ac2d3h4s5c9dJh",
+            "2c3d4h5s6c9dJh",
+            "TcJdQhKsAc2d3h",
+            "2c4c6c8cTc9dJh",
+            "2c2d2h3s3cAdAh",
+            "2c2d2h2sAcKdKh",
+            "Ac2c3c4c5c9dJh",
+            "AsKsQsJsTs2c2d",
+        ];
+        for pair in ladder.windows(2) {
+            assert!(rank(pair[0]) < rank(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(rank("AsKsQsJsTs2c2d"), 7461);
+        assert_eq!(rank("2c3c4c5c7d9hJs"), rank("2d3d4d5d7h9sJc"));
+        assert_eq!(rank("AcAdKhKsQc2d3h"), rank("AcAdKhKsQd9c8h"));
+
+        let small = Reference::new(Deck::new(6, 3).unwrap());
+        let rank = |s: &str| small.evaluate(parse(s).unwrap());
+
+        assert!(rank("Ac9dThJcQd") < rank("9cTdJhQcKd"));
+        assert!(rank("AcAdAh9cTd") < rank("Ac9dThJcQd"));
+
+        let short = Reference::new(Deck::SHORT);
+        let rank = |s: &str| short.evaluate(parse(s).unwrap());
+
+        assert_eq!(short.ranking, Ranking::Short);
+        assert!(rank("6c7c9cJcKd") < rank("7c7d7h6s6c"));
+        assert!(rank("6c7c9cJcKc") > rank("AcAdAhKsKc"));
+        assert!(rank("AcAdAhKsKc") > rank("6c7d8h9sTc"));
+        assert!(rank("Ac6d7h8s9c") < rank("6c7d8h9sTc"));
+        assert!(rank("Ac6d7h8s9c") > rank("AcAdAhKs9c"));
+        assert!(rank("Ac6c7c8c9c") > rank("AcAdAhAsKc"));
+    }
+
+    #[test]
+    #[ignore = "needs data/evaluator"]
+    fn test_reference_agrees_with_lookup() {
+        let lookup = Evaluator::new("data/evaluator".to_string());
+        let reference = Reference::new(Deck::STANDARD);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let cards: Vec<usize> = (0..52).collect();
+        let mut deal = || {
+            cards
+                .choose_multiple(&mut rng, 7)
+                .fold(0u64, |x, &c| x | 1 << c)
+        };
+
+        for _ in 0..100_000 {
+            let (a, b) = (deal(), deal());
+
+            assert_eq!(
+                HandEvaluator::evaluate(&lookup, a).cmp(&HandEvaluator::evaluate(&lookup, b)),
+                reference.evaluate(a).cmp(&reference.evaluate(b)),
+                "{} vs {}",
+                crate::cards::to_string(a),
+                crate::cards::to_string(b)
+            );
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use std::{fmt::Display, io::Write, path::Path, str::FromStr};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::cards;
+use crate::chunks::Chunks;
+use crate::deck::Indexing;
+use crate::error::{Error, Result};
+use crate::histogram::{Bin, Histogram, F16};
+use crate::inspect::Street;
+use crate::mapped::Table;
+use crate::matrix::Matrix;
+use crate::pipeline::Pipeline;
+use crate::tables::{self, centroids, load};
+use crate::transitions::{flop_to_turn, turn_to_river, Transitions};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+    Npy,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Result<Self> {
+        match Path::new(path).extension().and_then(|x| x.to_str()) {
+            Some("csv") => Ok(Format::Csv),
+            Some("json") => Ok(Format::Json),
+            Some("npy") => Ok(Format::Npy),
+            _ => Err(Error::Unsupported(format!(
+                "{}: unknown export format",
+                path
+            ))),
+        }
+    }
+}
+
+pub trait Element: Copy {
+    const DESCR: &'static str;
+
+    fn put(self, buffer: &mut Vec<u8>);
+}
+
+macro_rules! element {
+    ($t:ty, $descr:expr) => {
+        impl Element for $t {
+            const DESCR: &'static str = $descr;
+
+            fn put(self, buffer: &mut Vec<u8>) {
+                buffer.extend(self.to_le_bytes());
+            }
+        }
+    };
+}
+
+element!(u8, "|u1");
+element!(u16, "<u2");
+element!(u32, "<u4");
+element!(u64, "<u8");
+element!(f32, "<f4");
+
+impl Element for usize {
+    const DESCR: &'static str = "<u8";
+
+    fn put(self, buffer: &mut Vec<u8>) {
+        buffer.extend((self as u64).to_le_bytes());
+    }
+}
+
+impl Element for F16 {
+    const DESCR: &'static str = "<f2";
+
+    fn put(self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.0.to_le_bytes());
+    }
+}
+
+pub struct Cards<'a> {
+    pub indexer: &'a Indexing,
+    pub round: usize,
+}
+
+impl Cards<'_> {
+    fn names(&self) -> Vec<String> {
+        (0..=self.round).map(|r| format!("cards{}", r)).collect()
+    }
+
+    fn columns(&self, i: usize) -> Vec<String> {
+        self.indexer
+            .unindex(i as u64, self.round)
+            .iter()
+            .map(|&mask| cards::to_string(mask))
+            .collect()
+    }
+}
+
+fn npy<T: Element>(path: &String, shape: &[usize], data: impl Iterator<Item = T>) -> Result<()> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        T::DESCR,
+        shape
+    );
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    tables::write(path, |writer| {
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+
+        let mut buffer = Vec::new();
+        for x in data {
+            x.put(&mut buffer);
+            if buffer.len() >= 1 << 16 {
+                writer.write_all(&buffer)?;
+                buffer.clear();
+            }
+        }
+        writer.write_all(&buffer)?;
+
+        Ok(())
+    })
+}
+
+fn csv(path: &String, names: &[String], rows: impl Iterator<Item = Vec<String>>) -> Result<()> {
+    tables::write(path, |writer| {
+        writeln!(writer, "{}", names.join(","))?;
+        for row in rows {
+            writeln!(writer, "{}", row.join(","))?;
+        }
+
+        Ok(())
+    })
+}
+
+fn json(path: &String, rows: impl Iterator<Item = Map<String, Value>>) -> Result<()> {
+    tables::write(path, |writer| {
+        writer.write_all(b"[")?;
+        for (i, row) in rows.enumerate() {
+            if i > 0 {
+                writer.write_all(b",\n")?;
+            }
+            serde_json::to_writer(&mut *writer, &row)?;
+        }
+        writer.write_all(b"]\n")?;
+
+        Ok(())
+    })
+}
+
+fn unsupported(format: Format, path: &str) -> Error {
+    Error::Unsupported(format!(
+        "{}: card columns cannot be written as {:?}",
+        path, format
+    ))
+}
+
+pub fn export_table<T: Element + Display + Serialize>(
+    path: &String,
+    table: &[T],
+    cards: Option<&Cards>,
+) -> Result<()> {
+    let format = Format::from_path(path)?;
+
+    println!("Exporting {}", path);
+
+    match format {
+        Format::Npy if cards.is_some() => Err(unsupported(format, path)),
+        Format::Npy => npy(path, &[table.len()], table.iter().copied()),
+        Format::Csv => {
+            let mut names = vec!["index".to_string()];
+            names.extend(cards.map(|c| c.names()).unwrap_or_default());
+            names.push("cluster".to_string());
+
+            csv(
+                path,
+                &names,
+                table.iter().enumerate().map(|(i, x)| {
+                    let mut row = vec![i.to_string()];
+                    row.extend(cards.map(|c| c.columns(i)).unwrap_or_default());
+                    row.push(x.to_string());
+                    row
+                }),
+            )
+        }
+        Format::Json => json(
+            path,
+            table.iter().enumerate().map(|(i, x)| {
+                let mut row = Map::new();
+                row.insert("index".to_string(), json!(i));
+                if let Some(c) = cards {
+                    for (name, column) in c.names().into_iter().zip(c.columns(i)) {
+                        row.insert(name, json!(column));
+                    }
+                }
+                row.insert("cluster".to_string(), json!(x));
+                row
+            }),
+        ),
+    }
+}
+
+pub fn export_matrix<T: Bin + Element>(
+    path: &String,
+    matrix: &Matrix<T>,
+    cards: Option<&Cards>,
+) -> Result<()> {
+    let format = Format::from_path(path)?;
+
+    println!("Exporting {}", path);
+
+    match format {
+        Format::Npy if cards.is_some() => Err(unsupported(format, path)),
+        Format::Npy => npy(path, &[matrix.n, matrix.m], matrix.x.iter().copied()),
+        Format::Csv => {
+            let mut names = vec!["index".to_string()];
+            names.extend(cards.map(|c| c.names()).unwrap_or_default());
+            names.extend((0..matrix.m).map(|j| format!("x{}", j)));
+
+            csv(
+                path,
+                &names,
+                matrix.rows().enumerate().map(|(i, h)| {
+                    let mut row = vec![i.to_string()];
+                    row.extend(cards.map(|c| c.columns(i)).unwrap_or_default());
+                    row.extend(h.iter().map(|x| x.to_f32().to_string()));
+                    row
+                }),
+            )
+        }
+        Format::Json => json(
+            path,
+            matrix.rows().enumerate().map(|(i, h)| {
+                let mut row = Map::new();
+                row.insert("index".to_string(), json!(i));
+                if let Some(c) = cards {
+                    for (name, column) in c.names().into_iter().zip(c.columns(i)) {
+                        row.insert(name, json!(column));
+                    }
+                }
+                row.insert(
+                    "x".to_string(),
+                    json!(h.iter().map(|x| x.to_f32()).collect::<Vec<_>>()),
+                );
+                row
+            }),
+        ),
+    }
+}
+
+pub fn export_transitions(path: &String, transitions: &Transitions) -> Result<()> {
+    let format = Format::from_path(path)?;
+
+    println!("Exporting {}", path);
+
+    let entries = || {
+        transitions
+            .rows
+            .iter()
+            .enumerate()
+            .flat_map(|(i, row)| row.pairs().map(move |(j, p)| (i, j, p)))
+    };
+
+    match format {
+        Format::Npy => {
+            let m = transitions.rows.iter().map(|row| row.n).max().unwrap_or(0);
+
+            let mut dense = Matrix::<f32>::new(transitions.rows.len(), m);
+            for (i, j, p) in entries() {
+                dense.row_mut(i)[j] = p;
+            }
+
+            npy(path, &[dense.n, dense.m], dense.x.into_iter())
+        }
+        Format::Csv => csv(
+            path,
+            &["from", "to", "probability"].map(String::from),
+            entries().map(|(i, j, p)| vec![i.to_string(), j.to_string(), p.to_string()]),
+        ),
+        Format::Json => json(
+            path,
+            transitions.rows.iter().enumerate().map(|(i, row)| {
+                let mut map = Map::new();
+                map.insert("from".to_string(), json!(i));
+                map.insert("weight".to_string(), json!(transitions.weights[i]));
+                map.insert("to".to_string(), json!(row.i));
+                map.insert("probability".to_string(), json!(row.x));
+                map
+            }),
+        ),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Artifact {
+    Table,
+    Histograms,
+    Centroids,
+    Transitions,
+}
+
+impl FromStr for Artifact {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(Artifact::Table),
+            "histograms" => Ok(Artifact::Histograms),
+            "centroids" => Ok(Artifact::Centroids),
+            "transitions" => Ok(Artifact::Transitions),
+            _ => Err(Error::Parse(s.to_string())),
+        }
+    }
+}
+
+fn table(pipeline: &Pipeline, street: Street) -> Result<Table> {
+    Table::open(&(pipeline.tables.clone() + street.name() + ".tbl"))
+}
+
+// the features of a street on their own scale, whichever way they were stored
+fn histograms(pipeline: &Pipeline, street: Street) -> Result<Matrix> {
+    fn rows<T: Bin>(rows: Vec<Vec<T>>) -> Result<Matrix> {
+        Matrix::from_rows(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(|x| x.to_f32()).collect())
+                .collect(),
+        )
+    }
+
+    let path = pipeline.histograms.clone() + street.name();
+
+    match street {
+        Street::Flop => rows(load::<Vec<Vec<u16>>>(&(path + ".bin"))?),
+        Street::Turn => rows(load::<Vec<Vec<u8>>>(&(path + ".bin"))?),
+        Street::River if Chunks::exists(&(path.clone() + "/")) => {
+            let chunks = Chunks::open(&(path + "/"))?;
+
+            let mut points = Matrix::new(0, chunks.size);
+            for c in 0..chunks.count() {
+                for row in chunks.load(c)?.rows() {
+                    let row: Vec<f32> = row.iter().map(|&x| chunks.dequantise(x)).collect();
+                    points.push(&row);
+                }
+            }
+
+            Ok(points)
+        }
+        Street::River => {
+            let points: Vec<Histogram> = load(&(path + ".bin"))?;
+            Matrix::from_rows(points.into_iter().map(|h| h.x).collect())
+        }
+    }
+}
+
+// writes one artifact of the pipeline, the format following the extension of `path`. card
+// columns only go with rows that are hands, so centroids and transitions never get them
+pub fn export(
+    pipeline: &Pipeline,
+    artifact: Artifact,
+    street: Street,
+    path: &String,
+    cards: bool,
+) -> Result<()> {
+    let indexer = street.indexer(&pipeline.deck);
+    let columns = cards.then_some(Cards {
+        indexer: &indexer,
+        round: 1,
+    });
+
+    match artifact {
+        Artifact::Table => export_table(path, &table(pipeline, street)?, columns.as_ref()),
+        Artifact::Histograms => {
+            export_matrix(path, &histograms(pipeline, street)?, columns.as_ref())
+        }
+        _ if cards => Err(Error::Unsupported(format!(
+            "{}: card columns for {:?}",
+            path, artifact
+        ))),
+        Artifact::Centroids => {
+            let table = table(pipeline, street)?;
+            let k = table.iter().map(|&p| p as usize + 1).max().unwrap_or(0);
+
+            export_matrix(
+                path,
+                &centroids(k, &table, &histograms(pipeline, street)?),
+                None,
+            )
+        }
+        Artifact::Transitions => {
+            let deck = &pipeline.deck;
+            let transitions = match street {
+                Street::Flop => flop_to_turn(
+                    deck,
+                    &table(pipeline, Street::Flop)?,
+                    &table(pipeline, Street::Turn)?,
+                )?,
+                Street::Turn => turn_to_river(
+                    deck,
+                    &table(pipeline, Street::Turn)?,
+                    &table(pipeline, Street::River)?,
+                )?,
+                Street::River => {
+                    return Err(Error::Unsupported(
+                        "transitions out of the river".to_string(),
+                    ))
+                }
+            };
+
+            export_transitions(path, &transitions)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::deck::Deck;
+    use crate::mapped;
+    use crate::sparse::Sparse;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_export_formats() {
+        let dir = temp_dir("export");
+        let table: Vec<u16> = vec![3, 1, 2];
+        let matrix = Matrix::from_rows(vec![vec![0.5f32, 0.25], vec![0.0, 1.0]]).unwrap();
+
+        let path = dir.clone() + "table.csv";
+        export_table(&path, &table, None).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "index,cluster\n0,3\n1,1\n2,2\n"
+        );
+
+        let path = dir.clone() + "matrix.json";
+        export_matrix(&path, &matrix, None).unwrap();
+        let value: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value[1]["x"], json!([0.0, 1.0]));
+
+        let path = dir.clone() + "matrix.npy";
+        export_matrix(&path, &matrix, None).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let header = std::str::from_utf8(&bytes[10..10 + len]).unwrap();
+        assert_eq!((10 + len) % 64, 0);
+        assert!(header.contains("'descr': '<f4'") && header.contains("'shape': (2, 2)"));
+        assert_eq!(&bytes[10 + len..10 + len + 4], &0.5f32.to_le_bytes());
+
+        let transitions = Transitions {
+            weights: vec![2.0, 1.0],
+            rows: vec![
+                Sparse::from_pairs(3, vec![(2, 0.25), (0, 0.75)]),
+                Sparse::from_pairs(3, vec![(1, 1.0)]),
+            ],
+        };
+
+        let path = dir.clone() + "transitions.csv";
+        export_transitions(&path, &transitions).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "from,to,probability\n0,0,0.75\n0,2,0.25\n1,1,1\n"
+        );
+
+        assert!(Format::from_path("table.bin").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_small_pipeline() {
+        let dir = temp_dir("export_pipeline");
+        let deck = Deck::new(4, 3).unwrap();
+        let pipeline = Pipeline::new(String::new(), dir.clone(), dir.clone())
+            .unwrap()
+            .with_deck(deck)
+            .unwrap();
+
+        for street in [Street::Flop, Street::Turn, Street::River] {
+            let n = street.indexer(&deck).count(1) as usize;
+            let labels: Vec<u16> = (0..n).map(|i| (i % 3) as u16).collect();
+            mapped::save(&(pipeline.tables.clone() + street.name() + ".tbl"), &labels).unwrap();
+        }
+        let n = Street::Flop.indexer(&deck).count(1) as usize;
+        let rows: Vec<Vec<u16>> = (0..n).map(|i| vec![(i % 3) as u16, 1]).collect();
+        tables::save(&(pipeline.histograms.clone() + "flop.bin"), &rows).unwrap();
+
+        // raw indexed decks still get card columns
+        let path = dir.clone() + "flop.csv";
+        export(&pipeline, Artifact::Table, Street::Flop, &path, true).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("index,cards0,cards1,cluster"));
+        let hand = Street::Flop.indexer(&deck).unindex(4, 1);
+        assert_eq!(
+            lines.nth(4).unwrap(),
+            format!(
+                "4,{},{},1",
+                cards::to_string(hand[0]),
+                cards::to_string(hand[1])
+            )
+        );
+
+        let path = dir.clone() + "centroids.json";
+        export(&pipeline, Artifact::Centroids, Street::Flop, &path, false).unwrap();
+        let value: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value[2]["x"], json!([2.0, 1.0]));
+        assert!(export(&pipeline, Artifact::Centroids, Street::Flop, &path, true).is_err());
+
+        let path = dir.clone() + "transitions.csv";
+        export(&pipeline, Artifact::Transitions, Street::Turn, &path, false).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("from,to,probability\n"));
+        assert!(export(
+            &pipeline,
+            Artifact::Transitions,
+            Street::River,
+            &path,
+            false
+        )
+        .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
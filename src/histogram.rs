@@ -1,68 +1,278 @@
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, Error},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use textplots::{Chart, Plot, Shape};
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct Histogram {
-    pub n: usize,
-    pub s: f32,
-    pub x: Vec<f32>,
+pub trait Bin: Copy + Default + Send + Sync + Serialize + DeserializeOwned + 'static {
+    fn to_f32(self) -> f32;
+
+    fn from_f32(x: f32) -> Self;
 }
 
-impl Histogram {
-    pub fn new(n: usize) -> Self {
-        Self {
-            n,
-            s: 0.0,
-            x: vec![0.0; n],
+impl Bin for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x
+    }
+}
+
+impl Bin for u8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x.round().clamp(0.0, u8::MAX as f32) as u8
+    }
+}
+
+impl Bin for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(x: f32) -> Self {
+        x.round().clamp(0.0, u16::MAX as f32) as u16
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct F16(pub u16);
+
+impl Bin for F16 {
+    fn to_f32(self) -> f32 {
+        let sign = (self.0 as u32 & 0x8000) << 16;
+        let exp = (self.0 as u32 >> 10) & 0x1f;
+        let man = self.0 as u32 & 0x3ff;
+
+        if exp == 0 {
+            let x = man as f32 / (1 << 24) as f32;
+            return if sign == 0 { x } else { -x };
+        }
+
+        let bits = if exp == 0x1f {
+            sign | 0x7f80_0000 | man << 13
+        } else {
+            sign | (exp + 112) << 23 | man << 13
+        };
+
+        f32::from_bits(bits)
+    }
+
+    fn from_f32(x: f32) -> Self {
+        let bits = x.to_bits();
+
+        let sign = (bits >> 16 & 0x8000) as u16;
+        let exp = (bits >> 23 & 0xff) as i32;
+        let man = bits & 0x7f_ffff;
+
+        if exp == 0xff {
+            return F16(sign | 0x7c00 | if man != 0 { 0x200 } else { 0 });
+        }
+
+        let e = exp - 112;
+        if e >= 0x1f {
+            return F16(sign | 0x7c00);
+        }
+
+        if e <= 0 {
+            if e < -10 {
+                return F16(sign);
+            }
+
+            let m = man | 0x80_0000;
+            let shift = (14 - e) as u32;
+
+            let half = 1 << (shift - 1);
+            let rest = m & ((1 << shift) - 1);
+
+            let mut h = (m >> shift) as u16;
+            if rest > half || (rest == half && h & 1 == 1) {
+                h += 1;
+            }
+
+            return F16(sign | h);
         }
+
+        let rest = man & 0x1fff;
+
+        let mut h = sign | (e as u16) << 10 | (man >> 13) as u16;
+        if rest > 0x1000 || (rest == 0x1000 && h & 1 == 1) {
+            h += 1;
+        }
+
+        F16(h)
     }
+}
+
+#[derive(Clone)]
+pub struct Histogram<T: Bin = f32> {
+    pub x: Vec<T>,
+}
+
+// stored as {n, s, x} like the original f32 histograms so the existing files still load.
+// n and s are derived from x, so on reading only n is checked against it
+impl<T: Bin> Serialize for Histogram<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Histogram", 3)?;
+        state.serialize_field("n", &self.n())?;
+        state.serialize_field("s", &self.s())?;
+        state.serialize_field("x", &self.x)?;
+        state.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "")]
+struct Stored<T: Bin> {
+    n: usize,
+    #[serde(rename = "s")]
+    _s: f32,
+    x: Vec<T>,
+}
+
+impl<'de, T: Bin> Deserialize<'de> for Histogram<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = Stored::<T>::deserialize(deserializer)?;
 
-    pub fn from(v: Vec<f32>) -> Self 
-    {
+        if stored.n != stored.x.len() {
+            return Err(D::Error::custom(format!(
+                "histogram claims {} bins but has {}",
+                stored.n,
+                stored.x.len()
+            )));
+        }
+
+        Ok(Self { x: stored.x })
+    }
+}
+
+impl<T: Bin> Histogram<T> {
+    pub fn new(n: usize) -> Self {
         Self {
-            n: v.len(),
-            s: v.iter().sum(),
-            x: v.into_iter().map(f32::into).collect(),
+            x: vec![T::default(); n],
         }
     }
 
-    pub fn put(&mut self, i: usize, x: f32) {
-        self.s += x;
+    pub fn from(v: Vec<T>) -> Self {
+        Self { x: v }
+    }
+
+    pub fn n(&self) -> usize {
+        self.x.len()
+    }
 
-        self.x[i] += x;
+    pub fn s(&self) -> f32 {
+        self.x.iter().map(|x| x.to_f32()).sum()
+    }
+
+    pub fn put(&mut self, i: usize, x: f32) {
+        self.x[i] = T::from_f32(self.x[i].to_f32() + x);
     }
 
     pub fn get(&self, i: usize) -> f32 {
-        if self.s == 0.0 {
-            0.0
-        }
-        else {
-            self.x[i]
+        self.x[i].to_f32()
+    }
+
+    pub fn convert<U: Bin>(&self) -> Histogram<U> {
+        Histogram {
+            x: self.x.iter().map(|x| U::from_f32(x.to_f32())).collect(),
         }
     }
 
-    pub fn average(mut self, n: usize) -> Self {
-        let n = n as f32;
+    pub fn value(&self, i: usize) -> f32 {
+        (i as f32 + 0.5) / self.n() as f32
+    }
+
+    fn probabilities(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        let s = self.s();
+        self.x.iter().enumerate().map(move |(i, x)| {
+            let p = if s > 0.0 { x.to_f32() / s } else { 0.0 };
+            (self.value(i), p)
+        })
+    }
+
+    fn moment(&self, k: i32) -> f32 {
+        let mean = self.mean();
+        self.probabilities()
+            .map(|(v, p)| p * (v - mean).powi(k))
+            .sum()
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.probabilities().map(|(v, p)| p * v).sum()
+    }
 
-        self.s /= n;
-        for i in 0..self.n {
-            self.x[i] /= n;
+    pub fn variance(&self) -> f32 {
+        self.moment(2)
+    }
+
+    pub fn sd(&self) -> f32 {
+        self.variance().sqrt()
+    }
+
+    pub fn skewness(&self) -> f32 {
+        let sd = self.sd();
+        if sd == 0.0 {
+            return 0.0;
         }
 
-        self
+        self.moment(3) / sd.powi(3)
     }
 
-    pub fn norm(mut self) -> Self {
-        if self.s == 0.0 {
-            return self;
+    pub fn entropy(&self) -> f32 {
+        -self
+            .probabilities()
+            .filter(|&(_, p)| p > 0.0)
+            .map(|(_, p)| p * p.ln())
+            .sum::<f32>()
+    }
+
+    pub fn cdf(&self) -> Histogram {
+        let mut s = 0.0;
+        Histogram::from(
+            self.probabilities()
+                .map(|(_, p)| {
+                    s += p;
+                    s
+                })
+                .collect(),
+        )
+    }
+
+    pub fn quantile(&self, q: f32) -> f32 {
+        let q = q.clamp(0.0, 1.0);
+        let n = self.n() as f32;
+
+        let mut s = 0.0;
+        for (i, (_, p)) in self.probabilities().enumerate() {
+            if p > 0.0 && s + p >= q {
+                return (i as f32 + (q - s) / p) / n;
+            }
+            s += p;
         }
 
-        for i in 0..self.n {
-            self.x[i] /= self.s;
+        if s > 0.0 {
+            1.0
+        } else {
+            0.0
         }
-        self.s = 1.0;
+    }
 
-        self
+    pub fn summary(&self) -> String {
+        format!(
+            "mean equity {:.2}, sd {:.2}, median {:.2}, skewness {:.2}, entropy {:.2}",
+            self.mean(),
+            self.sd(),
+            self.quantile(0.5),
+            self.skewness(),
+            self.entropy()
+        )
     }
 
     pub fn display(&self) {
@@ -70,50 +280,367 @@ impl Histogram {
             .x
             .iter()
             .enumerate()
-            .map(|(i, &x)| (i as f32 + 1.0, x))
+            .map(|(i, &x)| (i as f32 + 1.0, x.to_f32()))
             .collect::<Vec<_>>();
 
-        Chart::new(100, 30, 0.0, self.n as f32)
+        Chart::new(100, 30, 0.0, self.n() as f32)
             .lineplot(&Shape::Bars(&points))
             .nice();
     }
 }
 
-pub fn emd(a: &Histogram, b: &Histogram) -> f32 {
-    assert!((a.s - b.s).abs() < 1e-3);
+impl Histogram {
+    pub fn cumulative(mut self) -> Self {
+        let mut s = 0.0;
+        for x in self.x.iter_mut() {
+            s += *x;
+            *x = s;
+        }
+
+        self
+    }
+
+    pub fn average(mut self, n: usize) -> Self {
+        let n = n as f32;
 
-    let n = std::cmp::min(a.n, b.n);
+        for x in self.x.iter_mut() {
+            *x /= n;
+        }
 
+        self
+    }
+
+    pub fn norm(mut self) -> Self {
+        let s = self.s();
+        if s == 0.0 {
+            return self;
+        }
+
+        for x in self.x.iter_mut() {
+            *x /= s;
+        }
+
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Metric {
+    Emd,
+    Mse,
+    L1,
+    TotalVariation,
+    Hellinger,
+    ChiSquare,
+    JensenShannon,
+    Cosine,
+}
+
+impl Metric {
+    pub fn distance<A: Bin, B: Bin>(self, a: &Histogram<A>, b: &Histogram<B>) -> f32 {
+        match self {
+            Metric::Emd => emd(a, b),
+            Metric::Mse => mse(a, b),
+            _ => self.kernel(&a.x, &b.x),
+        }
+    }
+
+    pub fn kernel<A: Bin, B: Bin>(self, a: &[A], b: &[B]) -> f32 {
+        match self {
+            Metric::Emd => emd_kernel(a, b),
+            Metric::Mse => mse_kernel(a, b),
+            Metric::L1 => l1_kernel(a, b),
+            Metric::TotalVariation => total_variation_kernel(a, b),
+            Metric::Hellinger => hellinger_kernel(a, b),
+            Metric::ChiSquare => chi_square_kernel(a, b),
+            Metric::JensenShannon => jensen_shannon_kernel(a, b),
+            Metric::Cosine => cosine_kernel(a, b),
+        }
+    }
+
+    // the distance is a metric, or the square of one, so a point whose distance to its
+    // center is d can only be closer to centers within bound * d of that center
+    pub fn bound(self) -> f32 {
+        match self {
+            Metric::Emd | Metric::L1 | Metric::TotalVariation | Metric::Hellinger => 2.0,
+            Metric::Mse | Metric::ChiSquare | Metric::JensenShannon | Metric::Cosine => 4.0,
+        }
+    }
+}
+
+const LANES: usize = 8;
+
+pub fn emd_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
     let mut d = 0.0;
     let mut s = 0.0;
-    for i in 0..n {
-        s += a.get(i) - b.get(i);
+    for (x, y) in a.iter().zip(b) {
+        s += x.to_f32() - y.to_f32();
         d += s.abs();
     }
 
     d
 }
 
-pub fn mse(a: &Histogram, b: &Histogram) -> f32 {
-    let n = std::cmp::min(a.n, b.n);
+pub fn l1_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    let n = std::cmp::min(a.len(), b.len());
 
-    let mut d = 0.0;
-    for i in 0..n {
-        let delta = a.get(i) - b.get(i);
-        d += delta * delta;
+    let mut d = [0.0; LANES];
+
+    let x = a[..n].chunks_exact(LANES);
+    let y = b[..n].chunks_exact(LANES);
+
+    let mut r = 0.0;
+    for (p, q) in x.remainder().iter().zip(y.remainder()) {
+        r += (p.to_f32() - q.to_f32()).abs();
     }
 
-    d
+    for (x, y) in x.zip(y) {
+        for l in 0..LANES {
+            d[l] += (x[l].to_f32() - y[l].to_f32()).abs();
+        }
+    }
+
+    d.iter().sum::<f32>() + r
+}
+
+pub fn mse_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    let n = std::cmp::min(a.len(), b.len());
+
+    let mut d = [0.0; LANES];
+
+    let x = a[..n].chunks_exact(LANES);
+    let y = b[..n].chunks_exact(LANES);
+
+    let mut r = 0.0;
+    for (p, q) in x.remainder().iter().zip(y.remainder()) {
+        let delta = p.to_f32() - q.to_f32();
+        r += delta * delta;
+    }
+
+    for (x, y) in x.zip(y) {
+        for l in 0..LANES {
+            let delta = x[l].to_f32() - y[l].to_f32();
+            d[l] += delta * delta;
+        }
+    }
+
+    d.iter().sum::<f32>() + r
+}
+
+// the divergences compare a / sum(a) with b / sum(b); two empty histograms are identical,
+// and an empty histogram is as far as possible from any other
+fn normalised<A: Bin, B: Bin>(a: &[A], b: &[B], f: impl Fn(f32, f32) -> f32) -> Option<f32> {
+    let s: f32 = a.iter().map(|x| x.to_f32()).sum();
+    let t: f32 = b.iter().map(|x| x.to_f32()).sum();
+
+    match (s > 0.0, t > 0.0) {
+        (true, true) => Some(
+            a.iter()
+                .zip(b)
+                .map(|(x, y)| f(x.to_f32() / s, y.to_f32() / t))
+                .sum(),
+        ),
+        (false, false) => Some(0.0),
+        _ => None,
+    }
+}
+
+pub fn total_variation_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    normalised(a, b, |p, q| (p - q).abs()).map_or(1.0, |d| d / 2.0)
+}
+
+pub fn hellinger_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    normalised(a, b, |p, q| (p.sqrt() - q.sqrt()).powi(2)).map_or(1.0, |d| (d / 2.0).sqrt())
 }
 
-pub fn agg(input: Option<Histogram>, other: &Histogram) -> Option<Histogram> {
+pub fn chi_square_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    normalised(a, b, |p, q| {
+        if p + q > 0.0 {
+            (p - q).powi(2) / (p + q)
+        } else {
+            0.0
+        }
+    })
+    .unwrap_or(2.0)
+}
+
+pub fn jensen_shannon_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    let h = |p: f32, m: f32| if p > 0.0 { p * (p / m).ln() } else { 0.0 };
+
+    normalised(a, b, |p, q| {
+        let m = (p + q) / 2.0;
+        (h(p, m) + h(q, m)) / 2.0
+    })
+    .map_or(std::f32::consts::LN_2, |d| d.max(0.0))
+}
+
+pub fn cosine_kernel<A: Bin, B: Bin>(a: &[A], b: &[B]) -> f32 {
+    let (mut xy, mut xx, mut yy) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x.to_f32(), y.to_f32());
+        xy += x * y;
+        xx += x * x;
+        yy += y * y;
+    }
+
+    match (xx > 0.0, yy > 0.0) {
+        (true, true) => (1.0 - xy / (xx.sqrt() * yy.sqrt())).max(0.0),
+        (false, false) => 0.0,
+        _ => 1.0,
+    }
+}
+
+pub fn emd<A: Bin, B: Bin>(a: &Histogram<A>, b: &Histogram<B>) -> f32 {
+    let (s, t) = (a.s(), b.s());
+
+    assert!((s - t).abs() < 1e-3 * s.max(t).max(1.0));
+
+    emd_kernel(&a.x, &b.x)
+}
+
+pub fn mse<A: Bin, B: Bin>(a: &Histogram<A>, b: &Histogram<B>) -> f32 {
+    mse_kernel(&a.x, &b.x)
+}
+
+pub fn agg<T: Bin>(input: Option<Histogram>, other: &Histogram<T>) -> Option<Histogram> {
     match input {
         Some(mut h) => {
-            for i in 0..h.n {
+            for i in 0..h.n() {
                 h.put(i, other.get(i));
             }
             Some(h)
         }
-        None => Some(other.clone()),
+        None => Some(other.convert()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_original_format() {
+        #[derive(Serialize)]
+        struct Original {
+            n: usize,
+            s: f32,
+            x: Vec<f32>,
+        }
+
+        let bytes = bincode::serialize(&vec![Original {
+            n: 3,
+            s: 4.0,
+            x: vec![1.0, 0.0, 3.0],
+        }])
+        .unwrap();
+
+        let read: Vec<Histogram> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(read[0].x, vec![1.0, 0.0, 3.0]);
+        assert_eq!(bincode::serialize(&read).unwrap(), bytes);
+
+        let wrong = bincode::serialize(&Original {
+            n: 2,
+            s: 4.0,
+            x: vec![1.0, 0.0, 3.0],
+        })
+        .unwrap();
+        assert!(bincode::deserialize::<Histogram>(&wrong).is_err());
+
+        let compact = Histogram::<u8>::from(vec![2, 0, 5]);
+        let read: Histogram<u8> =
+            bincode::deserialize(&bincode::serialize(&compact).unwrap()).unwrap();
+        assert_eq!(read.x, compact.x);
+    }
+
+    #[test]
+    fn test_f16_round_trip() {
+        for x in [0.0, 1.0, -2.5, 0.333_333_34, 65504.0, 6.1e-5, 3.0e-7] {
+            let y = F16::from_f32(x).to_f32();
+            assert!((x - y).abs() <= x.abs() / 1024.0 + 6.0e-8, "{} -> {}", x, y);
+        }
+
+        assert_eq!(F16::from_f32(1.0), F16(0x3c00));
+        assert_eq!(F16::from_f32(1e9).to_f32(), f32::INFINITY);
+    }
+
+    #[test]
+    fn test_distances_on_compact_storage() {
+        let a = Histogram::<u8>::from(vec![10, 20, 16]);
+        let b = Histogram::from(vec![15.0, 15.0, 16.0]);
+
+        assert_eq!(emd(&a, &b), 5.0);
+        assert_eq!(mse(&a, &b), 50.0);
+        assert_eq!(emd(&a.convert::<F16>(), &b.convert::<u16>()), 5.0);
+
+        let a = Histogram::from((0..19).map(|x| (x * 7 % 5) as f32).collect());
+        let b = Histogram::from((0..19).map(|x| (x * 2 % 5) as f32).collect());
+
+        let mut s = 0.0;
+        let mut d = 0.0;
+        for i in 0..19 {
+            s += a.get(i) - b.get(i);
+            d += f32::abs(s);
+        }
+
+        assert_eq!(emd(&a, &b), d);
+        assert_eq!(
+            mse(&a, &b),
+            (0..19).map(|i| (a.get(i) - b.get(i)).powi(2)).sum::<f32>()
+        );
+    }
+
+    #[test]
+    fn test_divergences_and_zero_mass() {
+        let a = Histogram::from(vec![1.0, 1.0, 0.0, 0.0]);
+        let b = Histogram::<u8>::from(vec![0, 0, 3, 3]);
+        let c = Histogram::<u8>::from(vec![2, 2, 0, 0]);
+        let z = Histogram::<u8>::new(4);
+
+        let divergences = [
+            (Metric::TotalVariation, 1.0),
+            (Metric::Hellinger, 1.0),
+            (Metric::ChiSquare, 2.0),
+            (Metric::JensenShannon, std::f32::consts::LN_2),
+            (Metric::Cosine, 1.0),
+        ];
+
+        for (metric, max) in divergences {
+            assert!((metric.distance(&a, &b) - max).abs() < 1e-6, "{:?}", metric);
+            assert!(metric.distance(&a, &c).abs() < 1e-6, "{:?}", metric);
+            assert_eq!(metric.distance(&a, &z), max, "{:?}", metric);
+            assert_eq!(metric.distance(&z, &z), 0.0, "{:?}", metric);
+        }
+
+        let d = Histogram::from(vec![0.5, 0.25, 0.25, 0.0]);
+        assert!((Metric::TotalVariation.distance(&a, &d) - 0.25).abs() < 1e-6);
+        assert!((Metric::ChiSquare.distance(&a, &d) - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let h = Histogram::<u8>::from(vec![1, 0, 0, 1]);
+
+        assert_eq!(h.mean(), 0.5);
+        assert_eq!(h.variance(), 0.140625);
+        assert_eq!(h.skewness(), 0.0);
+        assert_eq!(h.entropy(), std::f32::consts::LN_2);
+        assert_eq!(h.cdf().x, vec![0.5, 0.5, 0.5, 1.0]);
+
+        assert_eq!(h.quantile(0.0), 0.0);
+        assert_eq!(h.quantile(0.25), 0.125);
+        assert_eq!(h.quantile(0.5), 0.25);
+        assert_eq!(h.quantile(0.75), 0.875);
+        assert_eq!(h.quantile(1.0), 1.0);
+
+        let h = Histogram::from(vec![0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(h.mean(), 0.375);
+        assert_eq!(h.sd(), 0.0);
+        assert_eq!(h.entropy(), 0.0);
+
+        let h = Histogram::from(vec![3.0, 1.0, 0.0, 0.0]);
+        assert!(h.skewness() > 0.0);
+
+        assert_eq!(Histogram::<u8>::new(4).mean(), 0.0);
+        assert_eq!(Histogram::<u8>::new(4).quantile(0.5), 0.0);
     }
 }
@@ -0,0 +1,334 @@
+use std::str::FromStr;
+
+use rand::prelude::*;
+
+use crate::cards;
+use crate::chunks::Chunks;
+use crate::deck::{Deck, Indexing};
+use crate::error::{check, Error, Result};
+use crate::histogram::{Bin, Histogram, Metric};
+use crate::mapped::Table;
+use crate::matrix::Matrix;
+use crate::pipeline::Pipeline;
+use crate::tables::{average_equity, load, lookup};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Street {
+    Flop,
+    Turn,
+    River,
+}
+
+impl Street {
+    pub fn name(self) -> &'static str {
+        match self {
+            Street::Flop => "flop",
+            Street::Turn => "turn",
+            Street::River => "river",
+        }
+    }
+
+    pub fn metric(self) -> Metric {
+        match self {
+            Street::Flop | Street::Turn => Metric::Emd,
+            Street::River => Metric::Mse,
+        }
+    }
+
+    pub fn equity(self) -> fn(&Histogram) -> f32 {
+        match self {
+            Street::Flop | Street::Turn => Histogram::mean,
+            Street::River => average_equity,
+        }
+    }
+
+    pub fn board(self) -> u32 {
+        match self {
+            Street::Flop => 3,
+            Street::Turn => 4,
+            Street::River => 5,
+        }
+    }
+
+    pub fn indexer(self, deck: &Deck) -> Indexing {
+        match self {
+            Street::Flop => deck.indexer(vec![2, 3]),
+            Street::Turn => deck.indexer(vec![2, 4]),
+            Street::River => deck.indexer(vec![2, 5]),
+        }
+    }
+}
+
+impl FromStr for Street {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flop" => Ok(Street::Flop),
+            "turn" => Ok(Street::Turn),
+            "river" => Ok(Street::River),
+            _ => Err(Error::Parse(s.to_string())),
+        }
+    }
+}
+
+pub struct Bucket {
+    pub street: Street,
+    pub id: u16,
+    pub canonical: usize,
+    pub combos: u64,
+    pub centroid: Histogram,
+    pub samples: Vec<Vec<String>>,
+}
+
+impl Bucket {
+    // river centroids average the win rate against each ochs cluster, so only their equity
+    // means anything, while flop and turn centroids are strength histograms
+    pub fn summary(&self) -> String {
+        match self.street {
+            Street::Flop | Street::Turn => self.centroid.summary(),
+            Street::River => format!("equity {:.2}", (self.street.equity())(&self.centroid)),
+        }
+    }
+
+    pub fn print(&self) {
+        println!("{} bucket {}", self.street.name(), self.id);
+
+        self.centroid.display();
+
+        println!("{}", self.summary());
+        println!(
+            "size: {} canonical indices, {} raw combos",
+            self.canonical, self.combos
+        );
+
+        for hand in &self.samples {
+            println!("  {}", hand.join(" | "));
+        }
+    }
+}
+
+struct Collector<'a, R: Rng> {
+    bucket: Bucket,
+    indexer: &'a Indexing,
+    sums: Vec<f64>,
+    seen: Vec<u64>,
+    size: usize,
+    rng: R,
+}
+
+impl<'a, R: Rng> Collector<'a, R> {
+    fn new(street: Street, id: u16, indexer: &'a Indexing, m: usize, size: usize, rng: R) -> Self {
+        Self {
+            bucket: Bucket {
+                street,
+                id,
+                canonical: 0,
+                combos: 0,
+                centroid: Histogram::new(m),
+                samples: Vec::new(),
+            },
+            indexer,
+            sums: vec![0.0; m],
+            seen: Vec::new(),
+            size,
+            rng,
+        }
+    }
+
+    fn add<T: Bin>(&mut self, i: usize, row: &[T]) {
+        for (s, x) in self.sums.iter_mut().zip(row) {
+            *s += x.to_f32() as f64;
+        }
+
+        let rounds = self.indexer.unindex(i as u64, 1);
+
+        self.bucket.canonical += 1;
+        self.bucket.combos += self.indexer.class_size(&rounds);
+
+        // reservoir sampling keeps a uniform sample of the bucket in one pass
+        if self.seen.len() < self.size {
+            self.seen.push(i as u64);
+        } else {
+            let j = self.rng.gen_range(0..self.bucket.canonical);
+            if j < self.size {
+                self.seen[j] = i as u64;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Bucket {
+        let n = self.bucket.canonical.max(1) as f64;
+        self.bucket.centroid = Histogram::from(self.sums.iter().map(|&s| (s / n) as f32).collect());
+
+        self.seen.sort_unstable();
+        self.bucket.samples = self
+            .seen
+            .iter()
+            .map(|&i| {
+                self.indexer
+                    .unindex(i, 1)
+                    .iter()
+                    .map(|&mask| cards::to_string(mask))
+                    .collect()
+            })
+            .collect();
+
+        self.bucket
+    }
+}
+
+pub fn inspect_matrix<T: Bin, R: Rng>(
+    deck: &Deck,
+    street: Street,
+    table: &[u16],
+    points: &Matrix<T>,
+    id: u16,
+    samples: usize,
+    rng: R,
+) -> Result<Bucket> {
+    check(table.len(), points.n)?;
+
+    let indexer = street.indexer(deck);
+
+    let mut collector = Collector::new(street, id, &indexer, points.m, samples, rng);
+    for (i, (&p, row)) in table.iter().zip(points.rows()).enumerate() {
+        if p == id {
+            collector.add(i, row);
+        }
+    }
+
+    Ok(collector.finish())
+}
+
+pub fn inspect_chunks<R: Rng>(
+    deck: &Deck,
+    street: Street,
+    table: &[u16],
+    chunks: &Chunks,
+    id: u16,
+    samples: usize,
+    rng: R,
+) -> Result<Bucket> {
+    check(table.len(), chunks.n)?;
+
+    let indexer = street.indexer(deck);
+
+    let mut collector = Collector::new(street, id, &indexer, chunks.size, samples, rng);
+    for c in 0..chunks.count() {
+        let range = chunks.range(c);
+        for ((i, &p), row) in range.clone().zip(&table[range]).zip(chunks.load(c)?.rows()) {
+            if p == id {
+                let row: Vec<f32> = row.iter().map(|&x| chunks.dequantise(x)).collect();
+                collector.add(i, &row);
+            }
+        }
+    }
+
+    Ok(collector.finish())
+}
+
+// the bucket of a hand written as hole/board, e.g. AsKd/2c3h4s
+pub fn bucket(pipeline: &Pipeline, street: Street, hand: &str) -> Result<u16> {
+    let cards = hand
+        .split('/')
+        .map(cards::parse)
+        .collect::<Result<Vec<u64>>>()?;
+
+    let table = Table::open(&(pipeline.tables.clone() + street.name() + ".tbl"))?;
+
+    lookup(
+        &table,
+        &street.indexer(&pipeline.deck),
+        &[2, street.board()],
+        &cards,
+    )
+}
+
+pub fn inspect(pipeline: &Pipeline, street: Street, id: u16, samples: usize) -> Result<Bucket> {
+    let table = Table::open(&(pipeline.tables.clone() + street.name() + ".tbl"))?;
+
+    let path = pipeline.histograms.clone() + street.name();
+    let rng = thread_rng();
+
+    match street {
+        Street::Flop => {
+            let points = Matrix::from_rows(load::<Vec<Vec<u16>>>(&(path + ".bin"))?)?;
+            inspect_matrix(&pipeline.deck, street, &table, &points, id, samples, rng)
+        }
+        Street::Turn => {
+            let points = Matrix::from_rows(load::<Vec<Vec<u8>>>(&(path + ".bin"))?)?;
+            inspect_matrix(&pipeline.deck, street, &table, &points, id, samples, rng)
+        }
+        Street::River if Chunks::exists(&(path.clone() + "/")) => {
+            let chunks = Chunks::open(&(path + "/"))?;
+            inspect_chunks(&pipeline.deck, street, &table, &chunks, id, samples, rng)
+        }
+        Street::River => {
+            let points: Vec<Histogram> = load(&(path + ".bin"))?;
+            let points = Matrix::from_rows(points.into_iter().map(|h| h.x).collect())?;
+            inspect_matrix(&pipeline.deck, street, &table, &points, id, samples, rng)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chunks::Writer;
+    use crate::tables::RIVER;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_small_bucket_summaries() {
+        let deck = Deck::new(4, 3).unwrap();
+
+        let n = Street::River.indexer(&deck).count(1) as usize;
+        let rows: Vec<Vec<f32>> = (0..n)
+            .map(|i| vec![(i % 3) as f32 * 0.5, 1.0, (i % 5) as f32 * 0.4])
+            .collect();
+        let table: Vec<u16> = (0..n).map(|i| (i % 4) as u16).collect();
+
+        let path = temp_dir("inspect");
+        let mut writer = Writer::new(&path, n, 3, 5000, RIVER).unwrap();
+        for (i, row) in rows.iter().enumerate() {
+            writer.push(i, row).unwrap();
+        }
+        let chunks = writer.finish().unwrap();
+
+        let points = Matrix::from_rows(rows.clone()).unwrap();
+        let rng = StdRng::seed_from_u64(0);
+        let exact = inspect_matrix(&deck, Street::River, &table, &points, 1, 3, rng).unwrap();
+        let rng = StdRng::seed_from_u64(0);
+        let chunked = inspect_chunks(&deck, Street::River, &table, &chunks, 1, 3, rng).unwrap();
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        // chunked centroids come back on the feature scale rather than the stored 0 to 255
+        let members: Vec<&Vec<f32>> = rows.iter().skip(1).step_by(4).collect();
+        let equity = members
+            .iter()
+            .map(|row| row.iter().sum::<f32>() / 3.0)
+            .sum::<f32>()
+            / members.len() as f32;
+        for bucket in [&exact, &chunked] {
+            assert_eq!(bucket.canonical, members.len());
+            assert_eq!(bucket.combos, members.len() as u64);
+            assert_eq!(bucket.samples.len(), 3);
+            assert_eq!(bucket.summary(), format!("equity {:.2}", equity));
+        }
+        for (a, b) in exact.centroid.x.iter().zip(&chunked.centroid.x) {
+            assert!((a - b).abs() <= RIVER / 510.0 + 1e-6);
+        }
+
+        let n = Street::Flop.indexer(&deck).count(1) as usize;
+        let points =
+            Matrix::from_rows((0..n).map(|i| vec![1u16, (i % 2) as u16, 1]).collect()).unwrap();
+        let table = vec![0; n];
+        let rng = StdRng::seed_from_u64(0);
+        let flop = inspect_matrix(&deck, Street::Flop, &table, &points, 0, 3, rng).unwrap();
+        assert_eq!(flop.summary(), flop.centroid.summary());
+        assert!(flop.summary().starts_with("mean equity"));
+    }
+}
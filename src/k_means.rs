@@ -1,144 +1,586 @@
 use core::f32;
+use std::path::Path;
 
 use rand::prelude::*;
 use rayon::prelude::*;
 
-use crate::histogram::Histogram;
+use serde::{Deserialize, Serialize};
 
-pub fn generate_centers(
+use crate::chunks::Chunks;
+use crate::error::Result;
+use crate::histogram::{Bin, Metric};
+use crate::matrix::Matrix;
+use crate::tables::{load, save};
+
+const SAMPLE: usize = 64;
+
+const BLOCK: usize = 1 << 12;
+
+// lloyd iterations never increase the distance, so a run stops once an iteration improves it
+// by less than this fraction, or after the iteration budget is spent
+const TOLERANCE: f64 = 1e-6;
+const ITERATIONS: usize = 300;
+
+pub fn generate_centers<T: Bin, R: Rng>(
     k: usize,
-    points: &Vec<Histogram>,
-    distance: fn(&Histogram, &Histogram) -> f32,
-    rng: &mut ThreadRng,
-) -> Vec<Histogram> {
-    let mut weights = vec![f32::MAX; points.len()];
-
-    let mut centers = vec![points.choose(rng).unwrap().clone()];
-    for _ in 1..k {
-        weights.par_iter_mut().enumerate().for_each(|(i, x)| {
-            *x = x.min(distance(centers.last().unwrap(), &points[i]));
-        });
+    points: &Matrix<T>,
+    metric: Metric,
+    rng: &mut R,
+) -> Matrix {
+    let mut centers = Matrix::new(0, points.m);
+    centers.push(points.row(rng.gen_range(0..points.n)));
+
+    extend_centers(k, centers, points, metric, rng)
+}
+
+pub fn extend_centers<T: Bin, R: Rng>(
+    k: usize,
+    mut centers: Matrix,
+    points: &Matrix<T>,
+    metric: Metric,
+    rng: &mut R,
+) -> Matrix {
+    let mut weights = vec![f32::MAX; points.n];
+
+    let mut done = 0;
+    while centers.n < k {
+        for c in done..centers.n {
+            let center = centers.row(c);
+
+            weights
+                .par_iter_mut()
+                .zip(points.par_rows())
+                .for_each(|(x, h)| {
+                    *x = x.min(metric.kernel(h, center));
+                });
+        }
+        done = centers.n;
 
         centers.push(
-            points[rand::distributions::WeightedIndex::new(&weights)
-                .unwrap()
-                .sample(rng)]
-            .clone(),
+            points.row(
+                rand::distributions::WeightedIndex::new(&weights)
+                    .unwrap()
+                    .sample(rng),
+            ),
         );
     }
 
     centers
 }
 
-fn calculate_center_distances(
-    centers: &Vec<Histogram>,
-    distance: fn(&Histogram, &Histogram) -> f32,
-) -> Vec<Vec<f32>> {
-    let k = centers.len();
+fn calculate_center_distances(centers: &Matrix, metric: Metric) -> Vec<Vec<f32>> {
+    let k = centers.n;
+
+    (0..k)
+        .into_par_iter()
+        .map(|i| {
+            (0..k)
+                .map(|j| {
+                    if i == j {
+                        0.0
+                    } else {
+                        metric.kernel(centers.row(i), centers.row(j))
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
 
-    let mut center_distances = vec![vec![0.0; k]; k];
-    for i in 0..k {
-        for j in 0..k {
-            if i < j {
-                let d = distance(&centers[i], &centers[j]);
-                center_distances[i][j] = d;
-                center_distances[j][i] = d;
+// points are assigned in parallel, but every total is summed in a fixed order so the result
+// does not depend on how the work was split between threads
+fn assign<T: Bin>(
+    points: &Matrix<T>,
+    centers: &Matrix,
+    center_distances: &[Vec<f32>],
+    assignments: &mut [u32],
+    metric: Metric,
+) -> (f64, Vec<f64>, Vec<usize>) {
+    let k = centers.n;
+    let m = centers.m;
+
+    let distances: Vec<f32> = points
+        .par_rows()
+        .zip(assignments.par_iter_mut())
+        .map(|(h, q)| {
+            let mut p = *q as usize;
+            let mut d = metric.kernel(h, centers.row(p));
+            for (j, center) in centers.rows().enumerate() {
+                if j != p && center_distances[p][j] < metric.bound() * d {
+                    let x = metric.kernel(h, center);
+                    if x < d {
+                        d = x;
+                        p = j;
+                    }
+                }
+            }
+            *q = p as u32;
+
+            d
+        })
+        .collect();
+
+    let dis = distances
+        .par_chunks(BLOCK)
+        .map(|block| block.iter().map(|&d| d as f64).sum::<f64>())
+        .collect::<Vec<f64>>()
+        .into_iter()
+        .sum();
+
+    let mut members = vec![Vec::new(); k];
+    for (i, &p) in assignments.iter().enumerate() {
+        members[p as usize].push(i);
+    }
+
+    let sums = members
+        .par_iter()
+        .map(|list| {
+            let mut sums = vec![0.0; m];
+            for &i in list {
+                for (s, x) in sums.iter_mut().zip(points.row(i)) {
+                    *s += x.to_f32() as f64;
+                }
+            }
+            sums
+        })
+        .collect::<Vec<Vec<f64>>>()
+        .concat();
+
+    let counts = members.iter().map(Vec::len).collect();
+
+    (dis, sums, counts)
+}
+
+fn update(centers: &mut Matrix, sums: &[f64], counts: &[usize]) {
+    let m = centers.m;
+
+    for (p, &count) in counts.iter().enumerate() {
+        if count > 0 {
+            for (c, s) in centers.row_mut(p).iter_mut().zip(&sums[p * m..]) {
+                *c = (s / count as f64) as f32;
             }
         }
     }
+}
 
-    center_distances
+// what a checkpoint was computed from, so one left behind by other points or parameters is
+// never resumed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Fingerprint {
+    pub k: usize,
+    pub n: usize,
+    pub m: usize,
+    pub metric: Metric,
+    pub digest: u64,
 }
 
-pub fn k_means(
-    k: usize,
+fn fnv(values: impl Iterator<Item = u64>) -> u64 {
+    values.fold(0xcbf2_9ce4_8422_2325, |h, x| {
+        (h ^ x).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+// rows are hashed in parallel and combined in row order
+pub fn digest<T: Bin>(points: &Matrix<T>) -> u64 {
+    let rows: Vec<u64> = points
+        .par_rows()
+        .map(|row| fnv(row.iter().map(|x| x.to_f32().to_bits() as u64)))
+        .collect();
+
+    fnv(rows.into_iter())
+}
+
+pub fn fingerprint<T: Bin>(k: usize, points: &Matrix<T>, metric: Metric) -> Fingerprint {
+    Fingerprint {
+        k,
+        n: points.n,
+        m: points.m,
+        metric,
+        digest: digest(points),
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Checkpoint {
+    pub k: usize,
+    pub fingerprint: Option<Fingerprint>,
+    pub run: usize,
+    pub iteration: usize,
+    pub seed: [u8; 32],
+    pub centers: Matrix,
+    pub assignments: Vec<u32>,
+    pub distance: f64,
+    pub best: f64,
+    pub idxs: Vec<u32>,
+    // centers of the best run, kept instead of idxs when the points are too many to store
+    pub optimum: Matrix,
+}
+
+impl Checkpoint {
+    pub fn new(k: usize, n: usize, seed: [u8; 32]) -> Self {
+        Self {
+            k,
+            fingerprint: None,
+            run: 0,
+            iteration: 0,
+            seed,
+            centers: Matrix::default(),
+            assignments: vec![0; n],
+            distance: f64::MAX,
+            best: f64::MAX,
+            idxs: vec![0; n],
+            optimum: Matrix::default(),
+        }
+    }
+}
+
+fn run<T: Bin>(
+    state: Checkpoint,
     m: usize,
-    points: &Vec<Histogram>,
-    combines: fn(Option<Histogram>, &Histogram) -> Option<Histogram>,
-    distance: fn(&Histogram, &Histogram) -> f32,
-) -> Vec<usize> {
-    let n = points.len();
+    points: &Matrix<T>,
+    metric: Metric,
+    save: impl FnMut(&Checkpoint) -> Result<()>,
+) -> Result<Vec<usize>> {
+    if metric != Metric::Emd {
+        return lloyd(state, m, points, metric, save);
+    }
 
-    let mut best = f32::MAX;
-    let mut idxs = vec![0; n];
+    // emd between histograms is the l1 distance between their cumulative histograms,
+    // and the mean of cumulative histograms is the cumulative histogram of the mean
+    match points.cumulative::<T>() {
+        Some(cdf) => lloyd(state, m, &cdf, Metric::L1, save),
+        None => lloyd(state, m, &cumulative(points), Metric::L1, save),
+    }
+}
 
-    println!("clustering {} points into {} clusters", n, k);
+fn cumulative<T: Bin>(points: &Matrix<T>) -> Matrix {
+    points.cumulative().expect("f32 holds any running sum")
+}
 
-    for _ in 0..m {
-        let mut centers: Vec<Histogram> = generate_centers(k, &points, distance, &mut thread_rng());
+fn lloyd<T: Bin>(
+    mut state: Checkpoint,
+    m: usize,
+    points: &Matrix<T>,
+    metric: Metric,
+    mut save: impl FnMut(&Checkpoint) -> Result<()>,
+) -> Result<Vec<usize>> {
+    let k = state.k;
+    let n = points.n;
 
-        println!("centers generated");
+    println!("clustering {} points into {} clusters", n, k);
 
-        let mut cur = vec![0; n];
+    while state.run < m {
+        if state.centers.is_empty() {
+            let mut rng = StdRng::from_seed(state.seed);
+            state.seed = rng.gen();
+
+            state.centers = generate_centers(k, points, metric, &mut rng);
+            state.assignments = vec![0; n];
+            state.iteration = 0;
+            state.distance = f64::MAX;
+
+            println!("centers generated");
+        } else if state.iteration > 0 {
+            println!(
+                "resuming run {} at iteration {}",
+                state.run, state.iteration
+            );
+        }
 
-        let mut cnt = 0;
-        let mut pre = f32::MAX;
         loop {
-            cnt += 1;
-
-            let center_distances = calculate_center_distances(&centers, distance);
-
-            let (pos, dis): (Vec<usize>, Vec<f32>) = points
-                .par_iter()
-                .enumerate()
-                .map(|(i, h)| {
-                    let mut p = cur[i];
-                    let mut d = distance(h, &centers[p]);
-                    for j in 0..k {
-                        if j != p && center_distances[p][j] < 2.0 * d {
-                            let x = distance(h, &centers[j]);
-                            if x < d {
-                                d = x;
-                                p = j;
-                            }
-                        }
-                    }
-                    (p, d)
-                })
-                .unzip();
+            let center_distances = calculate_center_distances(&state.centers, metric);
+
+            let mut pos = state.assignments.clone();
 
-            let dis = dis.into_iter().sum::<f32>();
+            let (dis, sums, counts) =
+                assign(points, &state.centers, &center_distances, &mut pos, metric);
 
-            if dis == pre {
-                if dis < best {
-                    best = dis;
-                    idxs = pos;
+            if state.distance - dis <= TOLERANCE * dis || state.iteration >= ITERATIONS {
+                if dis < state.best {
+                    state.best = dis;
+                    state.idxs = pos;
                 }
                 break;
             }
 
-            centers.par_iter_mut().enumerate().for_each(|(p, c)| {
-                let mut cluster = None;
+            update(&mut state.centers, &sums, &counts);
 
-                let mut count = 0;
-                for i in 0..n {
-                    if pos[i] == p {
-                        cluster = combines(cluster, &points[i]);
+            state.iteration += 1;
+            state.distance = dis;
+            state.assignments = pos;
 
-                        count += 1;
-                    }
-                }
+            println!("#{}: distance = {}", state.iteration, dis);
+
+            save(&state)?;
+        }
+
+        println!(
+            "distance = {} (convergence required {} iterations)",
+            state.distance,
+            state.iteration + 1
+        );
+
+        state.run += 1;
+        state.centers = Matrix::default();
+
+        save(&state)?;
+    }
+
+    println!("best distance = {}", state.best);
+
+    Ok(state.idxs.into_iter().map(|x| x as usize).collect())
+}
+
+pub fn k_means<T: Bin>(k: usize, m: usize, points: &Matrix<T>, metric: Metric) -> Vec<usize> {
+    let state = Checkpoint::new(k, points.n, thread_rng().gen());
+
+    run(state, m, points, metric, |_| Ok(())).expect("checkpointing is disabled")
+}
+
+pub fn k_means_warm<T: Bin>(centers: &Matrix, points: &Matrix<T>, metric: Metric) -> Vec<usize> {
+    let mut state = Checkpoint::new(centers.n, points.n, thread_rng().gen());
+    state.centers = match metric {
+        Metric::Emd => cumulative(centers),
+        _ => centers.clone(),
+    };
+
+    run(state, 1, points, metric, |_| Ok(())).expect("checkpointing is disabled")
+}
+
+fn resume(path: &String, fingerprint: Fingerprint) -> Option<Checkpoint> {
+    if !Path::new(path).exists() {
+        return None;
+    }
+
+    println!("Loading checkpoint {}", path);
+
+    match load::<Checkpoint>(path) {
+        Ok(state) if state.fingerprint == Some(fingerprint) => Some(state),
+        _ => {
+            println!("Ignoring stale checkpoint {}", path);
+            None
+        }
+    }
+}
+
+fn finish(path: &String) -> Result<()> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+fn saver(path: &String, every: usize) -> impl FnMut(&Checkpoint) -> Result<()> + '_ {
+    move |state| {
+        if state.centers.is_empty() || state.iteration % every == 0 {
+            save(path, state)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn k_means_checkpointed<T: Bin>(
+    k: usize,
+    m: usize,
+    points: &Matrix<T>,
+    metric: Metric,
+    path: &String,
+    every: usize,
+) -> Result<Vec<usize>> {
+    let fingerprint = fingerprint(k, points, metric);
+
+    let state = resume(path, fingerprint).unwrap_or_else(|| Checkpoint {
+        fingerprint: Some(fingerprint),
+        ..Checkpoint::new(k, points.n, thread_rng().gen())
+    });
 
-                if let Some(x) = cluster {
-                    *c = x.average(count);
+    let idxs = run(state, m, points, metric, saver(path, every))?;
+
+    finish(path)?;
+
+    Ok(idxs)
+}
+
+// every chunk is digested, which costs no more than one of the passes k-means makes anyway
+fn chunk_fingerprint(k: usize, chunks: &Chunks, metric: Metric) -> Result<Fingerprint> {
+    let digests = (0..chunks.count())
+        .map(|c| Ok(digest(&chunks.load(c)?)))
+        .collect::<Result<Vec<u64>>>()?;
+
+    Ok(Fingerprint {
+        k,
+        n: chunks.n,
+        m: chunks.size,
+        metric,
+        digest: fnv(digests.into_iter()),
+    })
+}
+
+fn assign_chunks(
+    chunks: &Chunks,
+    centers: &Matrix,
+    embed: bool,
+    metric: Metric,
+    cur: &mut [u16],
+) -> Result<(f64, Vec<f64>, Vec<usize>)> {
+    let k = centers.n;
+
+    let center_distances = calculate_center_distances(centers, metric);
+
+    let mut dis = 0.0;
+    let mut sums = vec![0.0; k * chunks.size];
+    let mut counts = vec![0; k];
+    for c in 0..chunks.count() {
+        let points = chunks.load(c)?;
+        let range = chunks.range(c);
+
+        let mut pos: Vec<u32> = cur[range.clone()].iter().map(|&x| x as u32).collect();
+
+        let (d, s, t) = if embed {
+            let points = cumulative(&points);
+            assign(&points, centers, &center_distances, &mut pos, metric)
+        } else {
+            assign(&points, centers, &center_distances, &mut pos, metric)
+        };
+
+        for (x, p) in cur[range].iter_mut().zip(pos) {
+            *x = p as u16;
+        }
+
+        dis += d;
+        sums.iter_mut().zip(s).for_each(|(x, y)| *x += y);
+        counts.iter_mut().zip(t).for_each(|(x, y)| *x += y);
+    }
+
+    Ok((dis, sums, counts))
+}
+
+// the assignments are far too large to checkpoint, so a resumed run starts them from zero and
+// the best run is kept as its centers
+fn lloyd_chunked(
+    mut state: Checkpoint,
+    m: usize,
+    chunks: &Chunks,
+    metric: Metric,
+    mut save: impl FnMut(&Checkpoint) -> Result<()>,
+) -> Result<Vec<u16>> {
+    let k = state.k;
+    let n = chunks.n;
+
+    let (embed, metric) = match metric {
+        Metric::Emd => (true, Metric::L1),
+        _ => (false, metric),
+    };
+
+    println!("clustering {} chunked points into {} clusters", n, k);
+
+    let mut cur: Vec<u16> = vec![0; n];
+    let mut idxs = None;
+
+    while state.run < m {
+        if state.centers.is_empty() {
+            let mut rng = StdRng::from_seed(state.seed);
+            state.seed = rng.gen();
+
+            let sample = chunks.sample(SAMPLE * k, &mut rng)?;
+
+            state.centers = if embed {
+                generate_centers(k, &cumulative(&sample), metric, &mut rng)
+            } else {
+                generate_centers(k, &sample, metric, &mut rng)
+            };
+            state.iteration = 0;
+            state.distance = f64::MAX;
+
+            cur = vec![0; n];
+
+            println!("centers generated from {} samples", sample.n);
+        } else if state.iteration > 0 {
+            println!(
+                "resuming run {} at iteration {}",
+                state.run, state.iteration
+            );
+        }
+
+        loop {
+            let (dis, sums, counts) =
+                assign_chunks(chunks, &state.centers, embed, metric, &mut cur)?;
+
+            if state.distance - dis <= TOLERANCE * dis || state.iteration >= ITERATIONS {
+                if dis < state.best {
+                    state.best = dis;
+                    state.optimum = state.centers.clone();
+                    idxs = Some(std::mem::take(&mut cur));
                 }
-            });
+                break;
+            }
+
+            update(&mut state.centers, &sums, &counts);
+
+            state.iteration += 1;
+            state.distance = dis;
 
-            pre = dis;
-            cur = pos;
+            println!("#{}: distance = {}", state.iteration, dis);
 
-            println!("#{}: distance = {}", cnt, dis);
+            save(&state)?;
         }
 
         println!(
             "distance = {} (convergence required {} iterations)",
-            pre, cnt
+            state.distance,
+            state.iteration + 1
         );
+
+        state.run += 1;
+        state.centers = Matrix::default();
+
+        save(&state)?;
+    }
+
+    println!("best distance = {}", state.best);
+
+    match idxs {
+        Some(idxs) => Ok(idxs),
+        // the best run finished before a restart, so its assignments are rebuilt from its centers
+        None => {
+            let mut idxs = vec![0; n];
+            assign_chunks(chunks, &state.optimum, embed, metric, &mut idxs)?;
+            Ok(idxs)
+        }
     }
+}
+
+pub fn k_means_chunked(k: usize, m: usize, chunks: &Chunks, metric: Metric) -> Result<Vec<u16>> {
+    assert!(k <= 1 << 16);
+
+    let state = Checkpoint::new(k, 0, thread_rng().gen());
+
+    lloyd_chunked(state, m, chunks, metric, |_| Ok(()))
+}
+
+pub fn k_means_chunked_checkpointed(
+    k: usize,
+    m: usize,
+    chunks: &Chunks,
+    metric: Metric,
+    path: &String,
+    every: usize,
+) -> Result<Vec<u16>> {
+    assert!(k <= 1 << 16);
+
+    let fingerprint = chunk_fingerprint(k, chunks, metric)?;
+
+    let state = resume(path, fingerprint).unwrap_or_else(|| Checkpoint {
+        fingerprint: Some(fingerprint),
+        ..Checkpoint::new(k, 0, thread_rng().gen())
+    });
 
-    println!("best distance = {}", best);
+    let idxs = lloyd_chunked(state, m, chunks, metric, saver(path, every))?;
 
-    idxs
+    finish(path)?;
+
+    Ok(idxs)
 }
 
 #[cfg(test)]
@@ -147,11 +589,13 @@ mod tests {
 
     use smallvec::{smallvec, SmallVec};
 
+    use crate::chunks::Writer;
     use crate::histogram::*;
+    use crate::testing::{single_thread, temp_dir};
 
     #[test]
     fn test_k_means_histograms_mse() {
-        let a = vec![
+        let a: Vec<Histogram> = vec![
             smallvec![1, 2, 3],
             smallvec![5, 7, 8],
             smallvec![1, 3, 3],
@@ -167,7 +611,7 @@ mod tests {
         })
         .collect();
 
-        let actual = k_means(3, 5, &a, agg, mse);
+        let actual = k_means(3, 5, &Matrix::from(&a[..]), Metric::Mse);
 
         assert!(actual[0] == actual[1]);
         assert!(actual[1] == actual[2]);
@@ -190,7 +634,7 @@ mod tests {
 
     #[test]
     fn test_k_means_histograms_emd() {
-        let a = vec![
+        let a: Vec<Histogram> = vec![
             smallvec![1, 2, 3],
             smallvec![5, 7, 8],
             smallvec![1, 3, 3],
@@ -206,7 +650,7 @@ mod tests {
         })
         .collect();
 
-        let actual = k_means(3, 5, &a, agg, emd);
+        let actual = k_means(3, 5, &Matrix::from(&a[..]), Metric::Emd);
 
         assert!(actual[0] == actual[1]);
         assert!(actual[1] == actual[2]);
@@ -226,4 +670,183 @@ mod tests {
 
         println!("{:?}", actual);
     }
+
+    #[test]
+    fn test_k_means_histograms_divergences() {
+        let a: Vec<Histogram> = vec![
+            vec![8.0, 1.0, 1.0, 0.0],
+            vec![9.0, 1.0, 0.0, 0.0],
+            vec![1.0, 8.0, 1.0, 0.0],
+            vec![0.0, 9.0, 1.0, 0.0],
+            vec![0.0, 1.0, 1.0, 8.0],
+            vec![0.0, 0.0, 1.0, 9.0],
+        ]
+        .into_iter()
+        .map(Histogram::from)
+        .collect();
+
+        let a = Matrix::from(&a[..]);
+
+        for metric in [
+            Metric::L1,
+            Metric::TotalVariation,
+            Metric::Hellinger,
+            Metric::ChiSquare,
+            Metric::JensenShannon,
+            Metric::Cosine,
+        ] {
+            let actual = k_means(3, 5, &a, metric);
+
+            assert!(actual[0] == actual[1], "{:?}", metric);
+            assert!(actual[2] == actual[3], "{:?}", metric);
+            assert!(actual[4] == actual[5], "{:?}", metric);
+            assert!(
+                actual[0] != actual[2] && actual[2] != actual[4],
+                "{:?}",
+                metric
+            );
+        }
+    }
+
+    #[test]
+    fn test_k_means_ignores_thread_count() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let a: Vec<Histogram> = (0..10000)
+            .map(|_| Histogram::from((0..8).map(|_| rng.gen_range(0.0..1.0)).collect()).norm())
+            .collect();
+
+        let a = Matrix::from(&a[..]);
+
+        let cluster = || {
+            let mut distances = Vec::new();
+            let idxs = run(Checkpoint::new(6, a.n, [3; 32]), 2, &a, Metric::Emd, |c| {
+                distances.push(c.distance);
+                Ok(())
+            })
+            .unwrap();
+
+            (idxs, distances)
+        };
+
+        assert_eq!(single_thread(cluster), cluster());
+    }
+
+    #[test]
+    fn test_k_means_resumes_from_checkpoint() {
+        let a: Vec<Histogram> = vec![
+            vec![1.0, 2.0, 3.0],
+            vec![1.0, 3.0, 3.0],
+            vec![1.0, 9.0, 1.0],
+            vec![3.0, 9.0, 2.0],
+            vec![9.0, 7.0, 2.0],
+            vec![6.0, 7.0, 1.0],
+        ]
+        .into_iter()
+        .map(|v| Histogram::from(v).norm())
+        .collect();
+
+        let a = Matrix::from(&a[..]);
+
+        let dir = temp_dir("k_means");
+        let path = dir.clone() + "ckpt";
+
+        let fresh = Checkpoint {
+            fingerprint: Some(fingerprint(3, &a, Metric::Mse)),
+            ..Checkpoint::new(3, a.n, [7; 32])
+        };
+
+        let expected = run(fresh.clone(), 2, &a, Metric::Mse, |_| Ok(())).unwrap();
+
+        // the first attempt dies right after saving its first iteration
+        let interrupted = run(fresh.clone(), 2, &a, Metric::Mse, |c| {
+            save(&path, c)?;
+            if c.iteration == 1 {
+                Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(interrupted.is_err());
+
+        let actual = k_means_checkpointed(3, 2, &a, Metric::Mse, &path, 1).unwrap();
+        assert_eq!(expected, actual);
+        assert!(!Path::new(&path).exists());
+
+        // a finished checkpoint from other points or another metric is not reused
+        let stale = Checkpoint {
+            run: 2,
+            idxs: vec![5; a.n],
+            ..fresh
+        };
+
+        let mut b = a.clone();
+        b.row_mut(0)[0] += 0.25;
+
+        save(&path, &stale).unwrap();
+        let idxs = k_means_checkpointed(3, 2, &b, Metric::Mse, &path, 1).unwrap();
+        assert!(idxs.iter().all(|&p| p < 3));
+
+        save(&path, &stale).unwrap();
+        let idxs = k_means_checkpointed(3, 2, &a, Metric::L1, &path, 1).unwrap();
+        assert!(idxs.iter().all(|&p| p < 3));
+        assert!(!Path::new(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_k_means_chunked_resumes_from_checkpoint() {
+        let dir = temp_dir("k_means_chunks");
+
+        let mut rng = StdRng::seed_from_u64(9);
+        let points: Vec<Vec<f32>> = (0..200)
+            .map(|_| (0..4).map(|_| rng.gen_range(0.0..1.0)).collect())
+            .collect();
+
+        let write = |path: &String, points: &[Vec<f32>]| {
+            let mut writer = Writer::new(path, points.len(), 4, 64, 1.0).unwrap();
+            for (i, point) in points.iter().enumerate() {
+                writer.push(i, point).unwrap();
+            }
+            writer.finish().unwrap()
+        };
+        let chunks = write(&dir, &points);
+
+        // points that differ only in the last chunk get another fingerprint
+        let mut other = points.clone();
+        other[199][0] = 1.0 - other[199][0];
+        let other = write(&(dir.clone() + "other/"), &other);
+        assert_ne!(
+            chunk_fingerprint(4, &chunks, Metric::Mse).unwrap(),
+            chunk_fingerprint(4, &other, Metric::Mse).unwrap()
+        );
+
+        let path = dir.clone() + "ckpt";
+
+        let fresh = Checkpoint {
+            fingerprint: Some(chunk_fingerprint(4, &chunks, Metric::Mse).unwrap()),
+            ..Checkpoint::new(4, 0, [11; 32])
+        };
+
+        let expected = lloyd_chunked(fresh.clone(), 2, &chunks, Metric::Mse, |_| Ok(())).unwrap();
+
+        // the first attempt dies once its first run is saved, so the resumed one has to
+        // rebuild that run's assignments from its centers
+        let interrupted = lloyd_chunked(fresh, 2, &chunks, Metric::Mse, |c| {
+            save(&path, c)?;
+            if c.run == 1 {
+                Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(interrupted.is_err());
+
+        let actual = k_means_chunked_checkpointed(4, 2, &chunks, Metric::Mse, &path, 1).unwrap();
+        assert!(!Path::new(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(expected, actual);
+    }
 }
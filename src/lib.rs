@@ -1,3 +1,22 @@
+pub mod cards;
+pub mod chunks;
+pub mod compare;
+pub mod deck;
+pub mod error;
+pub mod evaluator;
+pub mod export;
 pub mod histogram;
+pub mod inspect;
 pub mod k_means;
+pub mod mapped;
+pub mod matrix;
+pub mod metrics;
+pub mod omaha;
+pub mod pipeline;
+pub mod sampled;
+pub mod sparse;
+pub mod sweep;
 pub mod tables;
+#[cfg(test)]
+mod testing;
+pub mod transitions;
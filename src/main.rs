@@ -1,45 +1,29 @@
-use std::rc::Rc;
-
-use poker_evaluator::Evaluator;
-
-use poker_abstraction::*;
-
-
-pub fn main() {
-    let evaluator = Rc::new(Evaluator::new("data/evaluator".to_string()));
-
-    let path: String = "data/histograms/".to_string();
-
-    let strength = Rc::new(tables::get_strengths(
-        path.clone() + "strength.bin",
-        &evaluator,
-    ));
-
-    let file: String = "data/tables/".to_string();
-
-    let _flop = tables::get_flop_clusters(
-        2197,
-        file.clone() + "flop.bin",
-        path.clone() + "flop.bin",
-        &strength,
-    );
-    let _turn = tables::get_turn_clusters(
-        2197,
-        file.clone() + "turn.bin",
-        path.clone() + "turn.bin",
-        &strength,
-    );
-    let ochs = tables::get_ochs_clusters(
-        13,
-        file.clone() + "ochs.bin",
-        path.clone() + "ochs.bin",
-        &strength,
-    );
-    let _river = tables::get_river_clusters(
-        2197,
-        file.clone() + "river.bin",
-        path.clone() + "river.bin",
-        Rc::clone(&evaluator),
-        Rc::new(ochs),
-    );
+use poker_abstraction::{deck::Deck, error::Result, pipeline::Pipeline, sampled::Sampling};
+
+
+// usage: poker_abstraction [standard|short|<ranks>x<suits>] [--parallel] [--sampled]
+pub fn main() -> Result<()> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        std::env::args().skip(1).partition(|x| x.starts_with("--"));
+
+    let deck: Deck = match args.first() {
+        Some(x) => x.parse()?,
+        None => Deck::STANDARD,
+    };
+    let parallel = flags.iter().any(|x| x == "--parallel");
+    let sampling = flags.iter().any(|x| x == "--sampled").then(Sampling::default);
+
+    let pipeline = Pipeline::new(
+        "data/evaluator".to_string(),
+        "data/histograms/".to_string(),
+        "data/tables/".to_string(),
+    )?
+    .with_deck(deck)?
+    .with_parallel(parallel)
+    .with_sampling(sampling);
+
+    let (_flop, _turn, ochs) = pipeline.streets(2197, 2197, 13)?;
+    let _river = pipeline.river_chunked(2197, &ochs)?;
+
+    Ok(())
 }
@@ -0,0 +1,121 @@
+use std::{fs::File, io::Write, ops::Deref, path::Path};
+
+use memmap2::Mmap;
+
+use crate::error::{Error, Result};
+use crate::tables;
+
+const MAGIC: [u8; 8] = *b"PKABTBL1";
+
+const HEADER: usize = 64;
+
+const _: () = assert!(cfg!(target_endian = "little"));
+
+pub struct Table {
+    map: Mmap,
+    len: usize,
+}
+
+impl Table {
+    pub fn open(path: &String) -> Result<Self> {
+        tables::check_directory(path)?;
+
+        let file = File::open(path)?;
+
+        // SAFETY: tables are only ever replaced by an atomic rename, never modified in place.
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.len() < HEADER || map[..8] != MAGIC {
+            return Err(Error::Corrupt(format!("{}: bad table header", path)));
+        }
+
+        let size = u64::from_le_bytes(map[8..16].try_into().unwrap()) as usize;
+        let len = u64::from_le_bytes(map[16..24].try_into().unwrap()) as usize;
+
+        let expected = len.checked_mul(size).and_then(|b| b.checked_add(HEADER));
+        if size != std::mem::size_of::<u16>() || expected != Some(map.len()) {
+            return Err(Error::Corrupt(format!("{}: truncated table", path)));
+        }
+
+        Ok(Self { map, len })
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        let data = self.map[HEADER..].as_ptr();
+
+        assert_eq!(data.align_offset(std::mem::align_of::<u16>()), 0);
+
+        // SAFETY: the header check guarantees `len` little-endian u16s follow the header,
+        // and the mapping is page aligned so the data is aligned as well.
+        unsafe { std::slice::from_raw_parts(data as *const u16, self.len) }
+    }
+}
+
+impl Deref for Table {
+    type Target = [u16];
+
+    fn deref(&self) -> &[u16] {
+        self.as_slice()
+    }
+}
+
+pub fn save(path: &String, data: &[u16]) -> Result<()> {
+    tables::write(path, |writer| {
+        let mut header = [0; HEADER];
+        header[..8].copy_from_slice(&MAGIC);
+        header[8..16].copy_from_slice(&(std::mem::size_of::<u16>() as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&(data.len() as u64).to_le_bytes());
+
+        writer.write_all(&header)?;
+        for chunk in data.chunks(1 << 16) {
+            let bytes: Vec<u8> = chunk.iter().flat_map(|x| x.to_le_bytes()).collect();
+            writer.write_all(&bytes)?;
+        }
+
+        Ok(())
+    })
+}
+
+pub fn get<'a>(
+    path: &String,
+    f: Box<dyn Fn() -> Result<Vec<u16>> + Send + Sync + 'a>,
+) -> Result<Table> {
+    if Path::new(path).exists() {
+        println!("Mapping {}", path);
+    } else {
+        tables::check_directory(path)?;
+
+        save(path, &f()?)?;
+    }
+
+    Table::open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_table_round_trip() {
+        let dir = temp_dir("table");
+        let path = dir.clone() + "table.tbl";
+
+        let data: Vec<u16> = (0..100_000).map(|x| (x * 7 % 2197) as u16).collect();
+
+        save(&path, &data).unwrap();
+        assert_eq!(&*Table::open(&path).unwrap(), &data[..]);
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len((HEADER + 10) as u64).unwrap();
+        assert!(matches!(Table::open(&path), Err(Error::Corrupt(_))));
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(Table::open(&path), Err(Error::Corrupt(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
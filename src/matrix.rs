@@ -0,0 +1,112 @@
+use rayon::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{check, Result};
+use crate::histogram::{Bin, Histogram};
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct Matrix<T: Bin = f32> {
+    pub n: usize,
+    pub m: usize,
+    pub x: Vec<T>,
+}
+
+impl<T: Bin> Matrix<T> {
+    pub fn new(n: usize, m: usize) -> Self {
+        Self {
+            n,
+            m,
+            x: vec![T::default(); n * m],
+        }
+    }
+
+    pub fn from_rows(rows: Vec<Vec<T>>) -> Result<Self> {
+        let m = rows.first().map_or(0, |x| x.len());
+
+        let mut matrix = Self::new(0, m);
+        matrix.x.reserve(rows.len() * m);
+        for row in rows {
+            check(m, row.len())?;
+            matrix.x.extend(row);
+            matrix.n += 1;
+        }
+
+        Ok(matrix)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    pub fn row(&self, i: usize) -> &[T] {
+        &self.x[i * self.m..(i + 1) * self.m]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [T] {
+        &mut self.x[i * self.m..(i + 1) * self.m]
+    }
+
+    pub fn push<U: Bin>(&mut self, row: &[U]) {
+        assert_eq!(self.m, row.len());
+
+        self.x.extend(row.iter().map(|x| T::from_f32(x.to_f32())));
+        self.n += 1;
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &[T]> {
+        self.x.chunks_exact(self.m.max(1)).take(self.n)
+    }
+
+    pub fn par_rows(&self) -> impl IndexedParallelIterator<Item = &[T]> {
+        self.x.par_chunks_exact(self.m.max(1)).take(self.n)
+    }
+
+    pub fn histogram(&self, i: usize) -> Histogram<T> {
+        Histogram::from(self.row(i).to_vec())
+    }
+
+    pub fn cumulative<U: Bin>(&self) -> Option<Matrix<U>> {
+        let mut x = Vec::with_capacity(self.x.len());
+        for row in self.rows() {
+            let mut s = 0.0;
+            for v in row {
+                s += v.to_f32();
+
+                let c = U::from_f32(s);
+                if c.to_f32() != s {
+                    return None;
+                }
+                x.push(c);
+            }
+        }
+
+        Some(Matrix {
+            n: self.n,
+            m: self.m,
+            x,
+        })
+    }
+
+    pub fn convert<U: Bin>(&self) -> Matrix<U> {
+        Matrix {
+            n: self.n,
+            m: self.m,
+            x: self.x.iter().map(|x| U::from_f32(x.to_f32())).collect(),
+        }
+    }
+}
+
+impl<T: Bin> From<&[Histogram<T>]> for Matrix<T> {
+    fn from(histograms: &[Histogram<T>]) -> Self {
+        let m = histograms.first().map_or(0, |h| h.n());
+
+        let mut matrix = Self::new(0, m);
+        for h in histograms {
+            matrix.push(&h.x);
+        }
+
+        matrix
+    }
+}
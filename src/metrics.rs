@@ -0,0 +1,409 @@
+use std::path::Path;
+
+use rand::prelude::*;
+
+use rayon::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::Chunks;
+use crate::deck::Indexing;
+use crate::error::{check, Result};
+use crate::histogram::{Bin, Histogram, Metric};
+use crate::matrix::Matrix;
+use crate::tables::{self, centroids, centroids_chunked};
+
+const SILHOUETTE: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Cluster {
+    pub id: usize,
+    pub size: usize,
+    pub weight: f64,
+    pub distance: f64,
+    pub equity: f64,
+    pub variance: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub metric: Metric,
+    pub k: usize,
+    pub n: usize,
+    pub within: f64,
+    pub variance: f64,
+    pub gini: f64,
+    pub silhouette: f64,
+    pub davies_bouldin: f64,
+    pub clusters: Vec<Cluster>,
+}
+
+pub fn class_weights(indexer: &Indexing, round: usize) -> impl Fn(usize) -> f64 + Sync + '_ {
+    move |i| indexer.class_size(&indexer.unindex(i as u64, round)) as f64
+}
+
+struct Accumulator<'a, W: Fn(usize) -> f64, E: Fn(&Histogram) -> f32> {
+    metric: Metric,
+    centers: &'a Matrix,
+    weight: W,
+    equity: E,
+    sizes: Vec<usize>,
+    weights: Vec<f64>,
+    distances: Vec<f64>,
+    equities: Vec<f64>,
+    squares: Vec<f64>,
+    picked: &'a [usize],
+    sample: Vec<(usize, Vec<f32>)>,
+    scratch: Histogram,
+}
+
+// the silhouette is taken on a fixed seeded sample of rows, so chunks can collect their part
+// of it independently
+fn pick(n: usize) -> Vec<usize> {
+    let mut picked =
+        rand::seq::index::sample(&mut StdRng::seed_from_u64(0), n, SILHOUETTE.min(n)).into_vec();
+    picked.sort_unstable();
+
+    picked
+}
+
+impl<'a, W: Fn(usize) -> f64, E: Fn(&Histogram) -> f32> Accumulator<'a, W, E> {
+    fn new(metric: Metric, centers: &'a Matrix, weight: W, equity: E, picked: &'a [usize]) -> Self {
+        let k = centers.n;
+
+        Self {
+            metric,
+            centers,
+            weight,
+            equity,
+            sizes: vec![0; k],
+            weights: vec![0.0; k],
+            distances: vec![0.0; k],
+            equities: vec![0.0; k],
+            squares: vec![0.0; k],
+            picked,
+            sample: Vec::new(),
+            scratch: Histogram::new(centers.m),
+        }
+    }
+
+    fn add<T: Bin>(&mut self, i: usize, p: usize, row: &[T]) {
+        for (x, y) in self.scratch.x.iter_mut().zip(row) {
+            *x = y.to_f32();
+        }
+
+        let w = (self.weight)(i);
+        let d = self.metric.kernel(row, self.centers.row(p)) as f64;
+        let e = (self.equity)(&self.scratch) as f64;
+
+        self.sizes[p] += 1;
+        self.weights[p] += w;
+        self.distances[p] += w * d;
+        self.equities[p] += w * e;
+        self.squares[p] += w * e * e;
+
+        if self.picked.binary_search(&i).is_ok() {
+            self.sample.push((p, self.scratch.x.clone()));
+        }
+    }
+
+    // chunks are merged in order, so the sums don't depend on how they were scheduled
+    fn merge(&mut self, other: Self) {
+        for p in 0..self.centers.n {
+            self.sizes[p] += other.sizes[p];
+            self.weights[p] += other.weights[p];
+            self.distances[p] += other.distances[p];
+            self.equities[p] += other.equities[p];
+            self.squares[p] += other.squares[p];
+        }
+
+        self.sample.extend(other.sample);
+    }
+
+    fn silhouette(&self) -> f64 {
+        let k = self.centers.n;
+
+        let mut total = 0.0;
+        for (p, x) in &self.sample {
+            let mut sums = vec![0.0; k];
+            let mut counts = vec![0; k];
+            for (q, y) in &self.sample {
+                sums[*q] += self.metric.kernel(x, y) as f64;
+                counts[*q] += 1;
+            }
+
+            if counts[*p] <= 1 {
+                continue;
+            }
+
+            let a = sums[*p] / (counts[*p] - 1) as f64;
+            let b = (0..k)
+                .filter(|&q| q != *p && counts[q] > 0)
+                .map(|q| sums[q] / counts[q] as f64)
+                .fold(f64::MAX, f64::min);
+
+            if b != f64::MAX && a.max(b) > 0.0 {
+                total += (b - a) / a.max(b);
+            }
+        }
+
+        total / self.sample.len().max(1) as f64
+    }
+
+    fn finish(self, n: usize) -> Report {
+        let k = self.centers.n;
+
+        let clusters: Vec<Cluster> = (0..k)
+            .map(|p| {
+                let w = self.weights[p].max(f64::MIN_POSITIVE);
+                let equity = self.equities[p] / w;
+
+                Cluster {
+                    id: p,
+                    size: self.sizes[p],
+                    weight: self.weights[p],
+                    distance: self.distances[p] / w,
+                    equity,
+                    variance: (self.squares[p] / w - equity * equity).max(0.0),
+                }
+            })
+            .collect();
+
+        let total: f64 = self.weights.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+
+        let within = self.distances.iter().sum::<f64>() / total;
+        let variance = clusters.iter().map(|c| c.weight * c.variance).sum::<f64>() / total;
+
+        let mut sizes: Vec<f64> = clusters.iter().map(|c| c.weight).collect();
+        sizes.sort_by(f64::total_cmp);
+        let gini = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (2.0 * (i + 1) as f64 - k as f64 - 1.0) * x)
+            .sum::<f64>()
+            / (k.max(1) as f64 * total);
+
+        let used: Vec<&Cluster> = clusters.iter().filter(|c| c.size > 0).collect();
+        let davies_bouldin = used
+            .iter()
+            .map(|a| {
+                used.iter()
+                    .filter(|b| a.id != b.id)
+                    .map(|b| {
+                        let m = self
+                            .metric
+                            .kernel(self.centers.row(a.id), self.centers.row(b.id))
+                            as f64;
+                        if m > 0.0 {
+                            (a.distance + b.distance) / m
+                        } else {
+                            0.0
+                        }
+                    })
+                    .fold(0.0, f64::max)
+            })
+            .sum::<f64>()
+            / used.len().max(1) as f64;
+
+        Report {
+            metric: self.metric,
+            k,
+            n,
+            within,
+            variance,
+            gini,
+            silhouette: self.silhouette(),
+            davies_bouldin,
+            clusters,
+        }
+    }
+}
+
+pub fn evaluate<T: Bin, I: Copy + Into<usize>>(
+    k: usize,
+    idxs: &[I],
+    points: &Matrix<T>,
+    metric: Metric,
+    weight: impl Fn(usize) -> f64,
+    equity: impl Fn(&Histogram) -> f32,
+) -> Result<Report> {
+    check(points.n, idxs.len())?;
+
+    let k = idxs.iter().map(|&p| p.into() + 1).fold(k, usize::max);
+
+    let centers = centroids(k, idxs, points);
+    let picked = pick(points.n);
+
+    let mut accumulator = Accumulator::new(metric, &centers, weight, equity, &picked);
+    for (i, (&p, row)) in idxs.iter().zip(points.rows()).enumerate() {
+        accumulator.add(i, p.into(), row);
+    }
+
+    Ok(accumulator.finish(points.n))
+}
+
+pub fn evaluate_chunks(
+    k: usize,
+    idxs: &[u16],
+    chunks: &Chunks,
+    metric: Metric,
+    weight: impl Fn(usize) -> f64 + Sync,
+    equity: impl Fn(&Histogram) -> f32 + Sync,
+) -> Result<Report> {
+    check(chunks.n, idxs.len())?;
+
+    let k = idxs.iter().map(|&p| p as usize + 1).fold(k, usize::max);
+
+    let centers = centroids_chunked(k, idxs, chunks)?;
+    let picked = pick(chunks.n);
+
+    let parts = (0..chunks.count())
+        .into_par_iter()
+        .map(|c| {
+            let mut accumulator = Accumulator::new(metric, &centers, &weight, &equity, &picked);
+
+            let range = chunks.range(c);
+            for ((i, &p), row) in range.clone().zip(&idxs[range]).zip(chunks.load(c)?.rows()) {
+                accumulator.add(i, p as usize, row);
+            }
+
+            Ok(accumulator)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut accumulator = Accumulator::new(metric, &centers, &weight, &equity, &picked);
+    for part in parts {
+        accumulator.merge(part);
+    }
+
+    Ok(accumulator.finish(chunks.n))
+}
+
+pub fn report_path(table: &str) -> String {
+    Path::new(table)
+        .with_extension("json")
+        .display()
+        .to_string()
+}
+
+pub fn write_report(table: &str, f: impl FnOnce() -> Result<Report>) -> Result<()> {
+    let path = report_path(table);
+    if Path::new(&path).exists() {
+        return Ok(());
+    }
+
+    let report = f()?;
+
+    println!(
+        "within = {:.4}, variance = {:.4}, gini = {:.4}, silhouette = {:.4}, davies-bouldin = {:.4}",
+        report.within, report.variance, report.gini, report.silhouette, report.davies_bouldin
+    );
+
+    tables::write(&path, |writer| {
+        Ok(serde_json::to_writer_pretty(writer, &report)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chunks::Writer;
+    use crate::testing::{single_thread, temp_dir};
+
+    #[test]
+    fn test_report_on_separated_clusters() {
+        let points = Matrix::from_rows(vec![
+            vec![1.0, 0.0, 0.0],
+            vec![0.9, 0.1, 0.0],
+            vec![0.0, 0.0, 1.0],
+            vec![0.0, 0.1, 0.9],
+        ])
+        .unwrap();
+
+        let report = evaluate(
+            2,
+            &[0usize, 0, 1, 1],
+            &points,
+            Metric::Emd,
+            |i| if i == 0 { 3.0 } else { 1.0 },
+            Histogram::mean,
+        )
+        .unwrap();
+
+        assert_eq!(report.clusters[0].size, 2);
+        assert_eq!(report.clusters[0].weight, 4.0);
+        assert!(report.clusters[0].equity < report.clusters[1].equity);
+        assert!((report.gini - 1.0 / 6.0).abs() < 1e-9);
+        assert!(report.silhouette > 0.8);
+        assert!(report.davies_bouldin < 0.2);
+        assert!(report.within < 0.1 && report.variance < 0.01);
+
+        let uniform = evaluate(
+            2,
+            &[0usize, 1, 0, 1],
+            &points,
+            Metric::Emd,
+            |_| 1.0,
+            Histogram::mean,
+        )
+        .unwrap();
+
+        assert_eq!(uniform.gini, 0.0);
+        assert!(uniform.silhouette < 0.0);
+        assert!(uniform.davies_bouldin > report.davies_bouldin);
+    }
+
+    #[test]
+    fn test_chunked_report_matches_in_memory() {
+        let path = temp_dir("metrics");
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 2500;
+
+        let mut writer = Writer::new(&path, n, 4, 300, 1.0).unwrap();
+        for i in 0..n {
+            let row: Vec<f32> = (0..4).map(|_| rng.gen_range(0.0..1.0)).collect();
+            writer.push(i, &row).unwrap();
+        }
+        let chunks = writer.finish().unwrap();
+
+        let rows = (0..chunks.count())
+            .flat_map(|c| {
+                chunks
+                    .load(c)
+                    .unwrap()
+                    .rows()
+                    .map(<[u8]>::to_vec)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let points: Matrix<u8> = Matrix::from_rows(rows).unwrap();
+
+        let idxs: Vec<u16> = (0..n).map(|i| (i * 7 % 5) as u16).collect();
+        let weight = |i: usize| (i % 3 + 1) as f64;
+        let equity = |h: &Histogram| h.get(0);
+
+        let memory = evaluate(5, &idxs, &points, Metric::Mse, weight, equity).unwrap();
+        let chunked = || evaluate_chunks(5, &idxs, &chunks, Metric::Mse, weight, equity).unwrap();
+
+        let report = chunked();
+        for (a, b) in [
+            (report.within, memory.within),
+            (report.variance, memory.variance),
+            (report.gini, memory.gini),
+            (report.silhouette, memory.silhouette),
+            (report.davies_bouldin, memory.davies_bouldin),
+        ] {
+            assert!((a - b).abs() <= 1e-6 * b.abs().max(1.0), "{} != {}", a, b);
+        }
+
+        let single = single_thread(chunked);
+        assert_eq!(
+            serde_json::to_string(&single).unwrap(),
+            serde_json::to_string(&report).unwrap()
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
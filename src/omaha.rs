@@ -0,0 +1,258 @@
+use rand::prelude::*;
+
+use rayon::prelude::*;
+
+use crate::deck::Deck;
+use crate::error::{check, Result};
+use crate::evaluator::HandEvaluator;
+use crate::histogram::{Histogram, Metric};
+use crate::k_means::k_means_checkpointed;
+use crate::matrix::Matrix;
+use crate::sampled::{hands, seeded};
+use crate::tables::{bucket, get, relabel, Source, BUCKETS};
+
+pub use crate::sampled::Sampling;
+
+fn subsets(mask: u64, n: usize) -> Vec<u64> {
+    let cards: Vec<u64> = (0..52).filter(|&c| mask >> c & 1 == 1).collect();
+
+    let mut result = Vec::new();
+    let mut stack = vec![(0, 0u64)];
+    while let Some((start, subset)) = stack.pop() {
+        if subset.count_ones() as usize == n {
+            result.push(subset);
+            continue;
+        }
+
+        for (j, &c) in cards.iter().enumerate().skip(start) {
+            stack.push((j + 1, subset | 1 << c));
+        }
+    }
+
+    result
+}
+
+// exactly two hole cards and three board cards
+pub fn evaluate<E: HandEvaluator + ?Sized>(evaluator: &E, hole: u64, board: u64) -> u16 {
+    let boards = subsets(board, 3);
+
+    subsets(hole, 2)
+        .into_iter()
+        .flat_map(|pair| boards.iter().map(move |&three| pair | three))
+        .map(|hand| evaluator.evaluate(hand))
+        .max()
+        .unwrap_or(0)
+}
+
+// river equity against one random four-card hand, ties counting half
+pub fn equity<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> f32 {
+    let ours = evaluate(evaluator, hole, board);
+
+    let opponents = hands(deck, hole | board, 4, sampling.opponents, rng);
+
+    let total: u32 = opponents
+        .iter()
+        .map(
+            |&other| match evaluate(evaluator, other, board).cmp(&ours) {
+                std::cmp::Ordering::Less => 2,
+                std::cmp::Ordering::Equal => 1,
+                std::cmp::Ordering::Greater => 0,
+            },
+        )
+        .sum();
+
+    total as f32 / (2 * opponents.len().max(1)) as f32
+}
+
+// distribution of strength buckets over the remaining board cards, scaled the way the hold'em
+// streets are so the histograms line up with theirs. a full board gives a single bucket
+pub fn histogram<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> Histogram {
+    let missing = 5 - board.count_ones() as usize;
+
+    let mut result = Histogram::new(BUCKETS);
+    for next in hands(deck, hole | board, missing, sampling.runouts, rng) {
+        let e = equity(deck, evaluator, hole, board | next, sampling, rng);
+        let s = (e * deck.strength()).round() as u16;
+        result.put(bucket(deck, s), 1.0);
+    }
+
+    result.norm()
+}
+
+pub fn generate_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    board: u8,
+    sampling: &Sampling,
+) -> Vec<Histogram> {
+    let indexer = deck.indexer(vec![4, board]);
+
+    (0..indexer.count(1))
+        .into_par_iter()
+        .map(|index| {
+            let val = indexer.unindex(index, 1);
+
+            histogram(
+                deck,
+                evaluator,
+                val[0],
+                val[1],
+                sampling,
+                &mut seeded(sampling, index),
+            )
+        })
+        .collect()
+}
+
+pub fn generate_flop_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Histogram> {
+    generate_histograms(deck, evaluator, 3, sampling)
+}
+
+pub fn generate_turn_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Histogram> {
+    generate_histograms(deck, evaluator, 4, sampling)
+}
+
+pub fn generate_river_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Histogram> {
+    generate_histograms(deck, evaluator, 5, sampling)
+}
+
+pub fn generate_river_equities<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<f32> {
+    let indexer = deck.indexer(vec![4, 5]);
+
+    (0..indexer.count(1))
+        .into_par_iter()
+        .map(|index| {
+            let val = indexer.unindex(index, 1);
+
+            equity(
+                deck,
+                evaluator,
+                val[0],
+                val[1],
+                sampling,
+                &mut seeded(sampling, index),
+            )
+        })
+        .collect()
+}
+
+// clusters the sampled histograms of a street the way the sampled hold'em streets are
+pub fn cluster(
+    deck: &Deck,
+    count: usize,
+    board: u8,
+    restarts: usize,
+    path: &String,
+    evaluator: Source<dyn HandEvaluator>,
+    sampling: &Sampling,
+) -> Result<Vec<usize>> {
+    println!("Sampling {}", path);
+
+    let histograms: Vec<Histogram> = get(
+        path,
+        Box::new(move || {
+            let evaluator = evaluator()?;
+            Ok(generate_histograms(deck, &*evaluator, board, sampling))
+        }),
+    )?;
+    let points = Matrix::from_rows(histograms.into_iter().map(|h| h.x).collect())?;
+
+    check(deck.indexer(vec![4, board]).count(1) as usize, points.n)?;
+    check(BUCKETS, points.m)?;
+
+    println!("Clustering {}", path);
+
+    let checkpoint = path.clone() + ".ckpt";
+    let idxs = k_means_checkpointed(count, restarts, &points, Metric::Emd, &checkpoint, 5)?;
+
+    Ok(relabel(count, &idxs, &points, Histogram::mean))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cards::parse;
+    use crate::evaluator::Reference;
+
+    #[test]
+    fn test_omaha_uses_two_hole_cards() {
+        let deck = Deck::new(6, 3).unwrap();
+        let reference = Reference::new(deck);
+
+        let board = parse("AcKcQcJc9d").unwrap();
+        let hole = parse("TcAdKdQh").unwrap();
+
+        let best = evaluate(&reference, hole, board);
+        assert!(best < reference.evaluate(parse("AcKcQcJcTc").unwrap()));
+        assert_eq!(best, reference.evaluate(parse("AcKdQcJcTc").unwrap()));
+
+        let exact = Sampling {
+            opponents: usize::MAX,
+            runouts: usize::MAX,
+            seed: 0,
+        };
+        let sampled = Sampling {
+            opponents: 60,
+            runouts: 8,
+            seed: 1,
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let e = equity(&deck, &reference, hole, board, &exact, &mut rng);
+        let s = equity(&deck, &reference, hole, board, &sampled, &mut rng);
+        assert!((0.0..=1.0).contains(&e));
+        assert!((e - s).abs() < 0.1);
+
+        let flop = parse("AcKc9d").unwrap();
+        let h = histogram(&deck, &reference, hole, flop, &sampled, &mut rng);
+        assert!((h.s() - 1.0).abs() < 1e-6);
+
+        let a = histogram(
+            &deck,
+            &reference,
+            hole,
+            flop,
+            &sampled,
+            &mut seeded(&sampled, 5),
+        );
+        let b = histogram(
+            &deck,
+            &reference,
+            hole,
+            flop,
+            &sampled,
+            &mut seeded(&sampled, 5),
+        );
+        assert_eq!(a.x, b.x);
+    }
+}
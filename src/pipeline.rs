@@ -0,0 +1,655 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use poker_evaluator::Evaluator;
+
+use crate::deck::Deck;
+use crate::error::{Error, Result};
+use crate::evaluator::{HandEvaluator, Reference};
+use crate::histogram::{Bin, Histogram, Metric};
+use crate::inspect::Street;
+use crate::mapped::{self, Table};
+use crate::matrix::Matrix;
+use crate::metrics::class_weights;
+use crate::omaha;
+use crate::sampled::{self, Degradation, Sampling};
+use crate::tables;
+
+const _: fn() = || {
+    fn shared<T: Send + Sync>() {}
+    shared::<Pipeline>();
+};
+
+pub struct Pipeline {
+    pub evaluator: String,
+    pub histograms: String,
+    pub tables: String,
+    pub deck: Deck,
+
+    roots: (String, String),
+    parallel: bool,
+    sampling: Option<Sampling>,
+    loaded: OnceLock<Arc<dyn HandEvaluator>>,
+    // held while the table is built so concurrent streets wait instead of building it twice
+    strength: Mutex<Option<Arc<Table>>>,
+}
+
+impl Pipeline {
+    pub fn new(evaluator: String, histograms: String, tables: String) -> Result<Self> {
+        for dir in [&histograms, &tables] {
+            if !Path::new(dir).is_dir() {
+                return Err(Error::MissingDirectory(dir.clone()));
+            }
+        }
+
+        Ok(Self {
+            evaluator,
+            roots: (histograms.clone(), tables.clone()),
+            histograms,
+            tables,
+            deck: Deck::STANDARD,
+            parallel: false,
+            sampling: None,
+            loaded: OnceLock::new(),
+            strength: Mutex::new(None),
+        })
+    }
+
+    // the lookup evaluator only covers the full deck, other decks fall back to the reference.
+    // their files go in a {ranks}x{suits}/ directory under each root so no run picks up
+    // tables built for another deck
+    pub fn with_deck(mut self, deck: Deck) -> Result<Self> {
+        let dir = |root: &String| {
+            if deck.is_standard() {
+                root.clone()
+            } else {
+                format!("{}{}x{}/", root, deck.ranks, deck.suits)
+            }
+        };
+
+        self.histograms = dir(&self.roots.0);
+        self.tables = dir(&self.roots.1);
+        for path in [&self.histograms, &self.tables] {
+            std::fs::create_dir_all(path)?;
+        }
+
+        self.deck = deck;
+        Ok(self)
+    }
+
+    pub fn with_evaluator(mut self, evaluator: Arc<dyn HandEvaluator>) -> Self {
+        self.loaded = OnceLock::from(evaluator);
+        self
+    }
+
+    // building the streets side by side holds all three histogram sets and k-means runs in
+    // memory at once
+    pub fn with_parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    // every street is built from sampled features, skipping the strength table, for a quick
+    // rough abstraction. the files are named after the sampling so they never mix with the
+    // exact ones
+    pub fn with_sampling(mut self, sampling: Option<Sampling>) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    fn name(&self, street: &str) -> String {
+        match &self.sampling {
+            Some(s) => format!("{}.{}x{}.{}", street, s.opponents, s.runouts, s.seed),
+            None => street.to_string(),
+        }
+    }
+
+    fn sampled(
+        &self,
+        street: &str,
+        n: usize,
+        board: u8,
+        restarts: usize,
+        sampling: &Sampling,
+    ) -> Result<Vec<usize>> {
+        sampled::cluster(
+            &self.deck,
+            n,
+            board,
+            restarts,
+            &(self.histograms.clone() + &self.name(street) + ".bin"),
+            &|| self.evaluator(),
+            sampling,
+        )
+    }
+
+    fn evaluator(&self) -> Result<Arc<dyn HandEvaluator>> {
+        // the lookup evaluator panics on a missing path, so check it first
+        if self.loaded.get().is_none()
+            && self.deck.is_standard()
+            && !Path::new(&self.evaluator).exists()
+        {
+            return Err(Error::MissingEvaluator(self.evaluator.clone()));
+        }
+
+        Ok(Arc::clone(self.loaded.get_or_init(|| {
+            println!("Loading Evaluator");
+
+            if self.deck.is_standard() {
+                Arc::new(Evaluator::new(self.evaluator.clone()))
+            } else {
+                Arc::new(Reference::new(self.deck))
+            }
+        })))
+    }
+
+    fn strength(&self) -> Result<Arc<Table>> {
+        let mut strength = self
+            .strength
+            .lock()
+            .map_err(|_| Error::Poisoned("strength table".to_string()))?;
+        if let Some(strength) = strength.as_ref() {
+            return Ok(Arc::clone(strength));
+        }
+
+        let table = Arc::new(tables::get_strengths(
+            &self.deck,
+            self.histograms.clone() + "strength.tbl",
+            &|| self.evaluator(),
+        )?);
+
+        *strength = Some(Arc::clone(&table));
+
+        Ok(table)
+    }
+
+    pub fn flop(&self, n: usize) -> Result<Table> {
+        if let Some(sampling) = &self.sampling {
+            return mapped::get(
+                &(self.tables.clone() + &self.name("flop") + ".tbl"),
+                Box::new(move || {
+                    let labels = self.sampled("flop", n, 3, 20, sampling)?;
+                    Ok(labels.into_iter().map(|x| x as u16).collect())
+                }),
+            );
+        }
+
+        tables::get_flop_clusters(
+            &self.deck,
+            n,
+            self.tables.clone() + "flop.tbl",
+            self.histograms.clone() + "flop.bin",
+            &|| self.strength(),
+        )
+    }
+
+    pub fn turn(&self, n: usize) -> Result<Table> {
+        if let Some(sampling) = &self.sampling {
+            return mapped::get(
+                &(self.tables.clone() + &self.name("turn") + ".tbl"),
+                Box::new(move || {
+                    let labels = self.sampled("turn", n, 4, 5, sampling)?;
+                    Ok(labels.into_iter().map(|x| x as u16).collect())
+                }),
+            );
+        }
+
+        tables::get_turn_clusters(
+            &self.deck,
+            n,
+            self.tables.clone() + "turn.tbl",
+            self.histograms.clone() + "turn.bin",
+            &|| self.strength(),
+        )
+    }
+
+    pub fn ochs(&self, n: usize) -> Result<Vec<usize>> {
+        if let Some(sampling) = &self.sampling {
+            return tables::get(
+                &(self.tables.clone() + &self.name("ochs") + ".bin"),
+                Box::new(move || self.sampled("ochs", n, 0, 95, sampling)),
+            );
+        }
+
+        tables::get_ochs_clusters(
+            &self.deck,
+            n,
+            self.tables.clone() + "ochs.bin",
+            self.histograms.clone() + "ochs.bin",
+            &|| self.strength(),
+        )
+    }
+
+    pub fn river(&self, n: usize, ochs: &[usize]) -> Result<Table> {
+        if let Some(sampling) = &self.sampling {
+            return mapped::get(
+                &(self.tables.clone() + &self.name("river") + ".tbl"),
+                Box::new(move || {
+                    sampled::cluster_rivers(
+                        &self.deck,
+                        n,
+                        &(self.histograms.clone() + &self.name("river") + ".bin"),
+                        &|| self.evaluator(),
+                        ochs,
+                        sampling,
+                    )
+                }),
+            );
+        }
+
+        tables::get_river_clusters(
+            &self.deck,
+            n,
+            self.tables.clone() + &self.name("river") + ".tbl",
+            self.histograms.clone() + &self.name("river") + ".bin",
+            &|| self.evaluator(),
+            ochs,
+        )
+    }
+
+    pub fn river_chunked(&self, n: usize, ochs: &[usize]) -> Result<Table> {
+        if let Some(sampling) = &self.sampling {
+            return mapped::get(
+                &(self.tables.clone() + &self.name("river") + ".tbl"),
+                Box::new(move || {
+                    sampled::cluster_rivers_chunked(
+                        &self.deck,
+                        n,
+                        &(self.histograms.clone() + &self.name("river") + "/"),
+                        &|| self.evaluator(),
+                        ochs,
+                        sampling,
+                    )
+                }),
+            );
+        }
+
+        tables::get_river_clusters_chunked(
+            &self.deck,
+            n,
+            self.tables.clone() + &self.name("river") + ".tbl",
+            self.histograms.clone() + &self.name("river") + "/",
+            &|| self.evaluator(),
+            ochs,
+        )
+    }
+
+    // omaha has no exact tables, so it is always sampled, with the pipeline's sampling or
+    // the default one
+    pub fn omaha(&self, street: Street, n: usize) -> Result<Table> {
+        let sampling = self.sampling.unwrap_or_default();
+        let name = format!(
+            "omaha.{}.{}x{}.{}",
+            street.name(),
+            sampling.opponents,
+            sampling.runouts,
+            sampling.seed
+        );
+        let histograms = self.histograms.clone() + &name + ".bin";
+
+        mapped::get(
+            &(self.tables.clone() + &name + ".tbl"),
+            Box::new(move || {
+                let labels = omaha::cluster(
+                    &self.deck,
+                    n,
+                    street.board() as u8,
+                    5,
+                    &histograms,
+                    &|| self.evaluator(),
+                    &sampling,
+                )?;
+                Ok(labels.into_iter().map(|x| x as u16).collect())
+            }),
+        )
+    }
+
+    // how far sampled flop or turn features and their clusters fall from the exact ones
+    pub fn degradation(
+        &self,
+        street: Street,
+        k: usize,
+        sampling: &Sampling,
+    ) -> Result<Degradation> {
+        fn normalised<T: Bin>(rows: Vec<Vec<T>>) -> Vec<Vec<f32>> {
+            rows.into_iter()
+                .map(|row| Histogram::from(row).convert::<f32>().norm().x)
+                .collect()
+        }
+
+        let strength = self.strength()?;
+        let (exact, board) = match street {
+            Street::Flop => (
+                normalised(tables::generate_flop_histograms(&self.deck, &strength)),
+                3,
+            ),
+            Street::Turn => (
+                normalised(tables::generate_turn_histograms(&self.deck, &strength)),
+                4,
+            ),
+            Street::River => {
+                return Err(Error::Unsupported(
+                    "degradation of river features".to_string(),
+                ))
+            }
+        };
+        let exact = Matrix::from_rows(exact)?;
+
+        let evaluator = self.evaluator()?;
+        let estimates = sampled::generate_histograms(&self.deck, &*evaluator, board, sampling);
+
+        let indexer = street.indexer(&self.deck);
+        sampled::degradation(
+            k,
+            1,
+            &exact,
+            &estimates,
+            Metric::Emd,
+            class_weights(&indexer, 1),
+        )
+    }
+
+    // flop, turn and ochs only share the strength table, so they can be built side by side
+    // when the pipeline is parallel
+    pub fn streets(
+        &self,
+        flop: usize,
+        turn: usize,
+        ochs: usize,
+    ) -> Result<(Table, Table, Vec<usize>)> {
+        if self.sampling.is_none() {
+            self.strength()?;
+        }
+
+        if !self.parallel {
+            return Ok((self.flop(flop)?, self.turn(turn)?, self.ochs(ochs)?));
+        }
+
+        let (a, (b, c)) = rayon::join(
+            || self.flop(flop),
+            || rayon::join(|| self.turn(turn), || self.ochs(ochs)),
+        );
+
+        Ok((a?, b?, c?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_decks_keep_separate_files() {
+        let dir = temp_dir("pipeline_decks");
+
+        let pipeline = |deck| {
+            Pipeline::new(String::new(), dir.clone(), dir.clone())
+                .unwrap()
+                .with_deck(deck)
+                .unwrap()
+        };
+
+        let standard = pipeline(Deck::STANDARD);
+        assert_eq!(standard.histograms, dir);
+
+        let short = pipeline(Deck::SHORT)
+            .with_deck(Deck::new(5, 3).unwrap())
+            .unwrap();
+        assert_eq!(short.histograms, dir.clone() + "5x3/");
+        assert_eq!(short.tables, dir.clone() + "5x3/");
+        assert!(Path::new(&short.tables).is_dir());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // ranks every hand backwards, so each strength flips to the other side of a tie
+    struct Reversed {
+        reference: Reference,
+        calls: AtomicUsize,
+    }
+
+    impl HandEvaluator for Reversed {
+        fn evaluate(&self, cards: u64) -> u16 {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            u16::MAX - self.reference.evaluate(cards)
+        }
+    }
+
+    #[test]
+    fn test_pipeline_uses_plugged_evaluator() {
+        let dir = temp_dir("pipeline_evaluator");
+        let deck = Deck::new(5, 2).unwrap();
+
+        let reversed = Arc::new(Reversed {
+            reference: Reference::new(deck),
+            calls: AtomicUsize::new(0),
+        });
+
+        let (histograms, tables) = (dir.clone() + "histograms/", dir.clone() + "tables/");
+        for path in [&histograms, &tables] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+
+        let pipeline = Pipeline::new(String::new(), histograms, tables)
+            .unwrap()
+            .with_deck(deck)
+            .unwrap()
+            .with_evaluator(reversed.clone());
+
+        let strength = pipeline.strength().unwrap();
+        let expected = tables::build_strengths(&deck, &Reference::new(deck));
+        assert!(reversed.calls.load(Ordering::Relaxed) > 0);
+        assert!(strength
+            .iter()
+            .zip(&expected)
+            .all(|(&a, &b)| a == deck.strength() as u16 - b));
+
+        let calls = reversed.calls.load(Ordering::Relaxed);
+        let ochs = pipeline.ochs(3).unwrap();
+        let river = pipeline.river(4, &ochs).unwrap();
+        assert!(reversed.calls.load(Ordering::Relaxed) > calls);
+        assert_eq!(river.len(), deck.indexer(vec![2, 5]).count(1) as usize);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_streets_serial_and_parallel() {
+        let dir = temp_dir("pipeline_streets");
+        let deck = Deck::new(5, 2).unwrap();
+
+        let pipeline = |name: &str, parallel| {
+            let (histograms, tables) = (
+                format!("{}{}/histograms/", dir, name),
+                format!("{}{}/tables/", dir, name),
+            );
+            for path in [&histograms, &tables] {
+                std::fs::create_dir_all(path).unwrap();
+            }
+
+            Pipeline::new(String::new(), histograms, tables)
+                .unwrap()
+                .with_deck(deck)
+                .unwrap()
+                .with_parallel(parallel)
+        };
+
+        // k-means seeds itself randomly, so only the features have to match between the two
+        let serial = pipeline("serial", false);
+        let parallel = pipeline("parallel", true);
+        for pipeline in [&serial, &parallel] {
+            let (flop, turn, ochs) = pipeline.streets(4, 4, 3).unwrap();
+            assert_eq!(flop.len(), deck.indexer(vec![2, 3]).count(1) as usize);
+            assert_eq!(turn.len(), deck.indexer(vec![2, 4]).count(1) as usize);
+            assert_eq!(ochs.len(), deck.indexer(vec![2]).count(0) as usize);
+            assert!(flop.iter().chain(turn.iter()).all(|&p| p < 4));
+            assert!(ochs.iter().all(|&p| p < 3));
+        }
+
+        for file in ["strength.tbl", "flop.bin", "turn.bin", "ochs.bin"] {
+            let read = |p: &Pipeline| std::fs::read(p.histograms.clone() + file).unwrap();
+            assert!(read(&serial) == read(&parallel));
+        }
+
+        let _ = std::panic::catch_unwind(|| {
+            let _held = serial.strength.lock().unwrap();
+            panic!("poison the strength lock");
+        });
+        assert!(matches!(serial.strength(), Err(Error::Poisoned(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sampled_streets_skip_strength() {
+        let dir = temp_dir("pipeline_sampled");
+        let deck = Deck::new(5, 2).unwrap();
+
+        let (histograms, tables) = (dir.clone() + "histograms/", dir.clone() + "tables/");
+        for path in [&histograms, &tables] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+
+        let sampling = Sampling {
+            opponents: 2,
+            runouts: 3,
+            seed: 1,
+        };
+        let pipeline = Pipeline::new(String::new(), histograms, tables)
+            .unwrap()
+            .with_deck(deck)
+            .unwrap()
+            .with_sampling(Some(sampling));
+
+        let (flop, turn, ochs) = pipeline.streets(4, 4, 3).unwrap();
+        assert_eq!(flop.len(), deck.indexer(vec![2, 3]).count(1) as usize);
+        assert_eq!(turn.len(), deck.indexer(vec![2, 4]).count(1) as usize);
+        assert_eq!(ochs.len(), deck.indexer(vec![2]).count(0) as usize);
+        assert!(ochs.iter().all(|&p| p < 3));
+
+        let river = pipeline.river(4, &ochs).unwrap();
+        assert_eq!(river.len(), deck.indexer(vec![2, 5]).count(1) as usize);
+        assert!(Path::new(&(pipeline.histograms.clone() + "river.2x3.1.bin")).exists());
+
+        std::fs::remove_file(pipeline.tables.clone() + "river.2x3.1.tbl").unwrap();
+        let chunked = pipeline.river_chunked(4, &ochs).unwrap();
+        assert_eq!(chunked.len(), river.len());
+        assert!(Path::new(&(pipeline.histograms.clone() + "river.2x3.1/")).is_dir());
+
+        assert!(!Path::new(&(pipeline.histograms.clone() + "strength.tbl")).exists());
+        for file in [
+            "flop.2x3.1.tbl",
+            "turn.2x3.1.tbl",
+            "ochs.2x3.1.bin",
+            "river.2x3.1.tbl",
+        ] {
+            assert!(Path::new(&(pipeline.tables.clone() + file)).exists());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_omaha_streets() {
+        let dir = temp_dir("pipeline_omaha");
+        // thirteen cards leave exactly one opponent hand once the board is out, and with a
+        // single suit that hand only ever wins, ties or loses
+        let deck = Deck::new(13, 1).unwrap();
+
+        let (histograms, tables) = (dir.clone() + "histograms/", dir.clone() + "tables/");
+        for path in [&histograms, &tables] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+
+        let sampling = Sampling {
+            opponents: 1,
+            runouts: 1,
+            seed: 1,
+        };
+        let pipeline = Pipeline::new(String::new(), histograms, tables)
+            .unwrap()
+            .with_deck(deck)
+            .unwrap()
+            .with_sampling(Some(sampling));
+
+        let flop = pipeline.omaha(Street::Flop, 2).unwrap();
+        assert_eq!(flop.len(), deck.indexer(vec![4, 3]).count(1) as usize);
+        assert!(flop.iter().all(|&p| p < 2));
+
+        let points: Vec<Histogram> =
+            tables::load(&(pipeline.histograms.clone() + "omaha.flop.1x1.1.bin")).unwrap();
+        for h in &points {
+            assert_eq!(h.n(), tables::BUCKETS);
+            assert!([0, tables::BUCKETS / 2, tables::BUCKETS - 1]
+                .iter()
+                .any(|&b| h.x[b] == 1.0));
+        }
+        assert!(Path::new(&(pipeline.tables.clone() + "omaha.flop.1x1.1.tbl")).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_degradation_of_sampled_features() {
+        let dir = temp_dir("pipeline_degradation");
+        let deck = Deck::new(5, 2).unwrap();
+
+        let (histograms, tables) = (dir.clone() + "histograms/", dir.clone() + "tables/");
+        for path in [&histograms, &tables] {
+            std::fs::create_dir_all(path).unwrap();
+        }
+
+        let pipeline = Pipeline::new(String::new(), histograms, tables)
+            .unwrap()
+            .with_deck(deck)
+            .unwrap();
+
+        let full = Sampling {
+            opponents: usize::MAX,
+            runouts: usize::MAX,
+            seed: 0,
+        };
+        let exact = pipeline.degradation(Street::Flop, 4, &full).unwrap();
+        assert!(exact.distance < 1e-6);
+        assert_eq!(exact.error, 0.0);
+
+        let rough = Sampling {
+            opponents: 2,
+            runouts: 3,
+            seed: 0,
+        };
+        let turn = pipeline.degradation(Street::Turn, 4, &rough).unwrap();
+        assert!(turn.distance > 0.0 && turn.error > 0.0);
+
+        assert!(matches!(
+            pipeline.degradation(Street::River, 4, &rough),
+            Err(Error::Unsupported(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_evaluator_is_an_error() {
+        let dir = temp_dir("pipeline_evaluator_missing");
+
+        let pipeline = Pipeline::new(dir.clone() + "evaluator", dir.clone(), dir.clone()).unwrap();
+        assert!(matches!(
+            pipeline.evaluator(),
+            Err(Error::MissingEvaluator(_))
+        ));
+
+        assert!(matches!(
+            Pipeline::new(String::new(), dir.clone() + "missing/", dir.clone()),
+            Err(Error::MissingDirectory(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,588 @@
+use rand::prelude::*;
+
+use rayon::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::{Chunks, Writer};
+use crate::compare::{compare, Comparison};
+use crate::deck::{choose, Deck, Indexing};
+use crate::error::{check, Result};
+use crate::evaluator::HandEvaluator;
+use crate::histogram::{Histogram, Metric};
+use crate::k_means::{k_means, k_means_checkpointed, k_means_warm};
+use crate::matrix::Matrix;
+use crate::metrics::evaluate;
+use crate::tables::{
+    average_equity, bucket, centroids, cluster_river_chunks, get, relabel, Source, BLOCK, BUCKETS,
+    CHUNK, RIVER,
+};
+
+// enumerate exhaustively below these sizes, otherwise draw this many samples
+#[derive(Clone, Copy, Debug)]
+pub struct Sampling {
+    pub opponents: usize,
+    pub runouts: usize,
+    pub seed: u64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            opponents: 1000,
+            runouts: 100,
+            seed: 0,
+        }
+    }
+}
+
+fn exhaustive(left: usize, n: usize, limit: usize) -> bool {
+    choose(left, n) <= limit as u64
+}
+
+fn draw<R: Rng>(deck: &Deck, used: u64, n: usize, rng: &mut R) -> u64 {
+    let cards: Vec<usize> = deck.cards().filter(|&c| used >> c & 1 == 0).collect();
+
+    cards
+        .choose_multiple(rng, n)
+        .fold(0, |mask, &c| mask | 1 << c)
+}
+
+pub(crate) fn hands<R: Rng>(
+    deck: &Deck,
+    used: u64,
+    n: usize,
+    limit: usize,
+    rng: &mut R,
+) -> Vec<u64> {
+    if exhaustive(deck.size() - used.count_ones() as usize, n, limit) {
+        deck.hands(n, used)
+    } else {
+        (0..limit).map(|_| draw(deck, used, n, rng)).collect()
+    }
+}
+
+// each index gets its own generator so the result does not depend on scheduling
+pub(crate) fn seeded(sampling: &Sampling, index: u64) -> StdRng {
+    StdRng::seed_from_u64(sampling.seed ^ index.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+// the feature together with the standard error of its mean equity on a 0 to 1 scale, zero
+// when every runout and opponent was enumerated
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Estimate {
+    pub histogram: Histogram,
+    pub error: f32,
+}
+
+fn points(theirs: u16, ours: u16) -> u32 {
+    match theirs.cmp(&ours) {
+        std::cmp::Ordering::Less => 2,
+        std::cmp::Ordering::Equal => 1,
+        std::cmp::Ordering::Greater => 0,
+    }
+}
+
+// equity against the sampled opponents, with the variance of that mean
+fn equity<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> (f64, f64) {
+    let ours = evaluator.evaluate(board | hole);
+
+    let opponents = hands(deck, hole | board, 2, sampling.opponents, rng);
+
+    let (mut sum, mut squares) = (0.0, 0.0);
+    for &other in &opponents {
+        let e = points(evaluator.evaluate(board | other), ours) as f64 / 2.0;
+        sum += e;
+        squares += e * e;
+    }
+
+    let n = opponents.len().max(1) as f64;
+    let mean = sum / n;
+
+    (mean, (squares / n - mean * mean).max(0.0) / n)
+}
+
+// the sampled opponents are scaled up to every opponent so the estimate falls on the same
+// buckets as the exact strength table
+pub fn strength<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> f32 {
+    (equity(deck, evaluator, hole, board, sampling, rng).0 * deck.strength() as f64) as f32
+}
+
+// distribution of strength buckets over the remaining board cards
+pub fn histogram<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> Estimate {
+    let missing = 5 - board.count_ones() as usize;
+
+    let runouts = hands(deck, hole | board, missing, sampling.runouts, rng);
+
+    let mut result = Histogram::new(BUCKETS);
+    let (mut sum, mut squares, mut noise) = (0.0, 0.0, 0.0);
+    for &next in &runouts {
+        let (e, v) = equity(deck, evaluator, hole, board | next, sampling, rng);
+        let s = (e * deck.strength() as f64).round() as u16;
+        result.put(bucket(deck, s), 1.0);
+
+        sum += e;
+        squares += e * e;
+        noise += v;
+    }
+
+    // the spread between sampled runouts, plus the noise of the sampled opponents within each
+    let r = runouts.len().max(1) as f64;
+    let mean = sum / r;
+
+    let mut variance = 0.0;
+    if !exhaustive(
+        deck.size() - 2 - board.count_ones() as usize,
+        missing,
+        sampling.runouts,
+    ) {
+        variance += (squares / r - mean * mean).max(0.0) / r;
+    }
+    if !exhaustive(deck.size() - 7, 2, sampling.opponents) {
+        variance += noise / (r * r);
+    }
+
+    Estimate {
+        histogram: result.norm(),
+        error: variance.sqrt() as f32,
+    }
+}
+
+pub fn generate_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    board: u8,
+    sampling: &Sampling,
+) -> Vec<Estimate> {
+    let indexer = deck.indexer(vec![2, board]);
+
+    (0..indexer.count(1))
+        .into_par_iter()
+        .map(|index| {
+            let val = indexer.unindex(index, 1);
+
+            histogram(
+                deck,
+                evaluator,
+                val[0],
+                val[1],
+                sampling,
+                &mut seeded(sampling, index),
+            )
+        })
+        .collect()
+}
+
+pub fn generate_flop_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Estimate> {
+    generate_histograms(deck, evaluator, 3, sampling)
+}
+
+pub fn generate_turn_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Estimate> {
+    generate_histograms(deck, evaluator, 4, sampling)
+}
+
+pub fn generate_ochs_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    sampling: &Sampling,
+) -> Vec<Estimate> {
+    let mapper = deck.indexer(vec![2]);
+
+    (0..mapper.count(0))
+        .into_par_iter()
+        .map(|index| {
+            let hole = mapper.unindex(index, 0)[0];
+
+            histogram(
+                deck,
+                evaluator,
+                hole,
+                0,
+                sampling,
+                &mut seeded(sampling, index),
+            )
+        })
+        .collect()
+}
+
+// clusters sampled features the way the exact flop, turn and ochs are clustered, without
+// building the strength table. no board cards gives the ochs
+pub fn cluster(
+    deck: &Deck,
+    count: usize,
+    board: u8,
+    restarts: usize,
+    path: &String,
+    evaluator: Source<dyn HandEvaluator>,
+    sampling: &Sampling,
+) -> Result<Vec<usize>> {
+    println!("Sampling {}", path);
+
+    let estimates: Vec<Estimate> = get(
+        path,
+        Box::new(move || {
+            let evaluator = evaluator()?;
+
+            Ok(match board {
+                0 => generate_ochs_histograms(deck, &*evaluator, sampling),
+                _ => generate_histograms(deck, &*evaluator, board, sampling),
+            })
+        }),
+    )?;
+    let points = Matrix::from_rows(estimates.into_iter().map(|e| e.histogram.x).collect())?;
+
+    let n = match board {
+        0 => deck.indexer(vec![2]).count(0),
+        _ => deck.indexer(vec![2, board]).count(1),
+    };
+    check(n as usize, points.n)?;
+    check(BUCKETS, points.m)?;
+
+    println!("Clustering {}", path);
+
+    let checkpoint = path.clone() + ".ckpt";
+    let idxs = k_means_checkpointed(count, restarts, &points, Metric::Emd, &checkpoint, 5)?;
+
+    Ok(relabel(count, &idxs, &points, Histogram::mean))
+}
+
+// average points against each opponent cluster, as in the exact river features
+#[allow(clippy::too_many_arguments)]
+pub fn river<E: HandEvaluator + ?Sized, R: Rng>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+    size: usize,
+    mapper: &Indexing,
+    hole: u64,
+    board: u64,
+    sampling: &Sampling,
+    rng: &mut R,
+) -> Estimate {
+    let ours = evaluator.evaluate(board | hole);
+
+    let opponents = hands(deck, hole | board, 2, sampling.opponents, rng);
+
+    let mut wins = vec![0.0; size];
+    let mut counts = vec![0.0; size];
+    let (mut sum, mut squares) = (0.0, 0.0);
+    for &other in &opponents {
+        let p = points(evaluator.evaluate(board | other), ours) as f32;
+        let k = ochs[mapper.index(&[other]) as usize];
+
+        wins[k] += p;
+        counts[k] += 1.0;
+        sum += p;
+        squares += p * p;
+    }
+
+    for (w, &c) in wins.iter_mut().zip(&counts) {
+        *w = if c > 0.0 { *w / c } else { 0.0 };
+    }
+
+    // points run from 0 to 2, so halve the error to express it as equity
+    let n = opponents.len().max(1) as f32;
+    let mean = sum / n;
+    let error = if exhaustive(deck.size() - 7, 2, sampling.opponents) {
+        0.0
+    } else {
+        ((squares / n - mean * mean).max(0.0) / n).sqrt() / 2.0
+    };
+
+    Estimate {
+        histogram: Histogram::from(wins),
+        error,
+    }
+}
+
+pub fn generate_river_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+    sampling: &Sampling,
+) -> Vec<Estimate> {
+    let size = ochs.iter().max().unwrap() + 1;
+
+    let mapper = deck.indexer(vec![2]);
+
+    let indexer = deck.indexer(vec![2, 5]);
+
+    (0..indexer.count(1))
+        .into_par_iter()
+        .map(|index| {
+            let val = indexer.unindex(index, 1);
+
+            river(
+                deck,
+                evaluator,
+                ochs,
+                size,
+                &mapper,
+                val[0],
+                val[1],
+                sampling,
+                &mut seeded(sampling, index),
+            )
+        })
+        .collect()
+}
+
+// the same rows as generate_river_histograms, streamed into chunks a block at a time
+pub fn generate_river_chunks<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+    sampling: &Sampling,
+    path: &String,
+    chunk: usize,
+) -> Result<Chunks> {
+    let size = ochs.iter().max().unwrap() + 1;
+
+    let mapper = deck.indexer(vec![2]);
+
+    let indexer = deck.indexer(vec![2, 5]);
+
+    let count = indexer.count(1);
+
+    let mut writer = Writer::new(path, count as usize, size, chunk, RIVER)?;
+    for start in (0..count).step_by(BLOCK) {
+        let block: Vec<Estimate> = (start..(start + BLOCK as u64).min(count))
+            .into_par_iter()
+            .map(|index| {
+                let val = indexer.unindex(index, 1);
+
+                river(
+                    deck,
+                    evaluator,
+                    ochs,
+                    size,
+                    &mapper,
+                    val[0],
+                    val[1],
+                    sampling,
+                    &mut seeded(sampling, index),
+                )
+            })
+            .collect();
+
+        for (i, e) in block.into_iter().enumerate() {
+            writer.push(start as usize + i, &e.histogram.x)?;
+        }
+    }
+
+    writer.finish()
+}
+
+pub fn cluster_rivers(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+    sampling: &Sampling,
+) -> Result<Vec<u16>> {
+    println!("Sampling {}", path);
+
+    let size = ochs.iter().max().map_or(0, |x| x + 1);
+
+    let estimates: Vec<Estimate> = get(
+        path,
+        Box::new(move || {
+            let evaluator = evaluator()?;
+            Ok(generate_river_histograms(deck, &*evaluator, ochs, sampling))
+        }),
+    )?;
+    let points = Matrix::from_rows(estimates.into_iter().map(|e| e.histogram.x).collect())?;
+
+    check(deck.indexer(vec![2, 5]).count(1) as usize, points.n)?;
+    check(size, points.m)?;
+
+    println!("Clustering {}", path);
+
+    let checkpoint = path.clone() + ".ckpt";
+    let idxs = k_means_checkpointed(count, 1, &points, Metric::Mse, &checkpoint, 1)?;
+
+    Ok(relabel(count, &idxs, &points, average_equity)
+        .into_iter()
+        .map(|x| x as u16)
+        .collect())
+}
+
+pub fn cluster_rivers_chunked(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+    sampling: &Sampling,
+) -> Result<Vec<u16>> {
+    println!("Sampling {}", path);
+
+    let size = ochs.iter().max().map_or(0, |x| x + 1);
+
+    let river = if Chunks::exists(path) {
+        Chunks::open(path)?
+    } else {
+        let evaluator = evaluator()?;
+        generate_river_chunks(deck, &*evaluator, ochs, sampling, path, CHUNK)?
+    };
+
+    cluster_river_chunks(deck, count, path, &river, size)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Degradation {
+    pub distance: f64,
+    pub error: f64,
+    pub exact: f64,
+    pub sampled: f64,
+    pub comparison: Comparison,
+}
+
+// clusters the sampled features starting from the exact centers so the two labelings line up,
+// then scores both labelings on the exact features
+pub fn degradation(
+    k: usize,
+    restarts: usize,
+    exact: &Matrix,
+    sampled: &[Estimate],
+    metric: Metric,
+    weight: impl Fn(usize) -> f64,
+) -> Result<Degradation> {
+    check(exact.n, sampled.len())?;
+
+    let points = Matrix::from_rows(sampled.iter().map(|e| e.histogram.x.clone()).collect())?;
+    check(exact.m, points.m)?;
+
+    let (mut distance, mut error, mut total) = (0.0, 0.0, 0.0);
+    for (i, (row, e)) in points.rows().zip(sampled).enumerate() {
+        let w = weight(i);
+
+        distance += w * metric.kernel(exact.row(i), row) as f64;
+        error += w * e.error as f64;
+        total += w;
+    }
+    let total = f64::max(total, f64::MIN_POSITIVE);
+
+    let idxs = k_means(k, restarts, exact, metric);
+    let approx = k_means_warm(&centroids(k, &idxs, exact), &points, metric);
+
+    Ok(Degradation {
+        distance: distance / total,
+        error: error / total,
+        exact: evaluate(k, &idxs, exact, metric, &weight, Histogram::mean)?.within,
+        sampled: evaluate(k, &approx, exact, metric, &weight, Histogram::mean)?.within,
+        comparison: compare(&idxs, &approx, &weight)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::Reference;
+    use crate::tables;
+
+    #[test]
+    fn test_sampled_histograms_converge() {
+        let deck = Deck::new(5, 2).unwrap();
+        let evaluator = Reference::new(deck);
+
+        let strength = tables::build_strengths(&deck, &evaluator);
+        let exact = Matrix::from_rows(
+            tables::generate_flop_histograms(&deck, &strength)
+                .into_iter()
+                .map(|h| {
+                    Histogram::from(h.into_iter().map(f32::from).collect())
+                        .norm()
+                        .x
+                })
+                .collect(),
+        )
+        .unwrap();
+
+        let full = Sampling {
+            opponents: usize::MAX,
+            runouts: usize::MAX,
+            seed: 0,
+        };
+        let enumerated = generate_flop_histograms(&deck, &evaluator, &full);
+        for (i, e) in enumerated.iter().enumerate() {
+            assert_eq!(e.error, 0.0);
+            assert_eq!(e.histogram.x, exact.row(i));
+        }
+
+        let ochs: Vec<usize> = (0..deck.indexer(vec![2]).count(0) as usize)
+            .map(|i| i % 3)
+            .collect();
+        let river = tables::generate_river_histograms(&deck, &evaluator, &ochs);
+        for (e, h) in generate_river_histograms(&deck, &evaluator, &ochs, &full)
+            .iter()
+            .zip(&river)
+        {
+            for (a, b) in e.histogram.x.iter().zip(&h.x) {
+                assert!((a - b).abs() < 1e-5);
+            }
+        }
+
+        let rough = |runouts| Sampling {
+            opponents: 2,
+            runouts,
+            seed: 7,
+        };
+
+        let a = generate_flop_histograms(&deck, &evaluator, &rough(3));
+        let b = generate_flop_histograms(&deck, &evaluator, &rough(3));
+        assert!(a
+            .iter()
+            .zip(&b)
+            .all(|(a, b)| a.histogram.x == b.histogram.x));
+        assert!(a.iter().any(|e| e.error > 0.0));
+
+        // every runout is enumerated, so only the opponents add to the error
+        let opponents = Sampling {
+            runouts: usize::MAX,
+            ..rough(0)
+        };
+        let turn = generate_turn_histograms(&deck, &evaluator, &opponents);
+        assert!(turn.iter().any(|e| e.error > 0.0));
+        assert!(turn.iter().all(|e| e.error < 0.5));
+
+        let fine = generate_flop_histograms(&deck, &evaluator, &rough(8));
+
+        let coarse = degradation(4, 1, &exact, &a, Metric::Emd, |_| 1.0).unwrap();
+        let close = degradation(4, 1, &exact, &fine, Metric::Emd, |_| 1.0).unwrap();
+        assert!(close.distance < coarse.distance);
+        assert!(close.error < coarse.error);
+        assert!(coarse.sampled >= coarse.exact * 0.9);
+        assert!((-1.0..=1.0).contains(&coarse.comparison.ari));
+    }
+}
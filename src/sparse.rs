@@ -0,0 +1,462 @@
+use serde::{Deserialize, Serialize};
+
+use crate::histogram::{Bin, Histogram};
+use crate::matrix::Matrix;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Sparse {
+    pub n: usize,
+    pub i: Vec<u32>,
+    pub x: Vec<f32>,
+}
+
+impl Sparse {
+    pub fn new(n: usize) -> Self {
+        Self {
+            n,
+            i: Vec::new(),
+            x: Vec::new(),
+        }
+    }
+
+    pub fn from_pairs(n: usize, mut pairs: Vec<(u32, f32)>) -> Self {
+        pairs.sort_unstable_by_key(|&(i, _)| i);
+
+        let mut sparse = Self::new(n);
+        for (i, x) in pairs {
+            assert!((i as usize) < n);
+
+            if sparse.i.last() == Some(&i) {
+                *sparse.x.last_mut().unwrap() += x;
+            } else if x != 0.0 {
+                sparse.i.push(i);
+                sparse.x.push(x);
+            }
+        }
+
+        sparse
+    }
+
+    pub fn from_dense<T: Bin>(h: &Histogram<T>) -> Self {
+        let mut sparse = Self::new(h.n());
+        for (i, x) in h.x.iter().enumerate() {
+            if x.to_f32() != 0.0 {
+                sparse.i.push(i as u32);
+                sparse.x.push(x.to_f32());
+            }
+        }
+
+        sparse
+    }
+
+    pub fn to_dense(&self) -> Histogram {
+        let mut h = Histogram::new(self.n);
+        for (&i, &x) in self.i.iter().zip(&self.x) {
+            h.x[i as usize] = x;
+        }
+
+        h
+    }
+
+    pub fn len(&self) -> usize {
+        self.i.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.i.is_empty()
+    }
+
+    pub fn s(&self) -> f32 {
+        self.x.iter().sum()
+    }
+
+    pub fn get(&self, i: usize) -> f32 {
+        match self.i.binary_search(&(i as u32)) {
+            Ok(p) => self.x[p],
+            Err(_) => 0.0,
+        }
+    }
+
+    pub fn put(&mut self, i: usize, x: f32) {
+        assert!(i < self.n);
+
+        match self.i.binary_search(&(i as u32)) {
+            Ok(p) => self.x[p] += x,
+            Err(p) => {
+                self.i.insert(p, i as u32);
+                self.x.insert(p, x);
+            }
+        }
+    }
+
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        self.i
+            .iter()
+            .map(|&i| i as usize)
+            .zip(self.x.iter().copied())
+    }
+
+    pub fn norm(mut self) -> Self {
+        let s = self.s();
+        if s == 0.0 {
+            return self;
+        }
+
+        for x in self.x.iter_mut() {
+            *x /= s;
+        }
+
+        self
+    }
+}
+
+fn merge<'a>(a: &'a Sparse, b: &'a Sparse) -> impl Iterator<Item = (usize, f32, f32)> + 'a {
+    let (mut p, mut q) = (0, 0);
+    std::iter::from_fn(move || {
+        let i = a.i.get(p).copied().unwrap_or(u32::MAX);
+        let j = b.i.get(q).copied().unwrap_or(u32::MAX);
+
+        match i.cmp(&j) {
+            _ if i == u32::MAX && j == u32::MAX => None,
+            std::cmp::Ordering::Less => {
+                p += 1;
+                Some((i as usize, a.x[p - 1], 0.0))
+            }
+            std::cmp::Ordering::Greater => {
+                q += 1;
+                Some((j as usize, 0.0, b.x[q - 1]))
+            }
+            std::cmp::Ordering::Equal => {
+                p += 1;
+                q += 1;
+                Some((i as usize, a.x[p - 1], b.x[q - 1]))
+            }
+        }
+    })
+}
+
+fn dense<'a, T: Bin>(a: &'a Sparse, b: &'a [T]) -> impl Iterator<Item = (usize, f32, f32)> + 'a {
+    let mut p = 0;
+    b.iter().enumerate().map(move |(i, y)| {
+        if a.i.get(p) == Some(&(i as u32)) {
+            p += 1;
+            (i, a.x[p - 1], y.to_f32())
+        } else {
+            (i, 0.0, y.to_f32())
+        }
+    })
+}
+
+fn linear(n: usize, pairs: impl Iterator<Item = (usize, f32, f32)>) -> f32 {
+    let mut d = 0.0;
+    let mut s: f32 = 0.0;
+    let mut last = 0;
+    for (i, x, y) in pairs {
+        d += s.abs() * (i - last) as f32;
+        s += x - y;
+        last = i;
+    }
+
+    d + s.abs() * (n - last) as f32
+}
+
+pub fn emd(a: &Sparse, b: &Sparse) -> f32 {
+    linear(a.n, merge(a, b))
+}
+
+pub fn emd_dense<T: Bin>(a: &Sparse, b: &[T]) -> f32 {
+    linear(b.len(), dense(a, b))
+}
+
+// earth mover's distance under any ground cost as a minimum cost flow from the bins of `a` to
+// the bins of `b`, found by successive shortest paths with potentials keeping every residual
+// edge non-negative. when the masses differ only the smaller one is moved and the surplus
+// stays where it is
+fn transport(a: &[(usize, f32)], b: &[(usize, f32)], ground: &Matrix) -> f32 {
+    let (p, q) = (a.len(), b.len());
+    let cost: Vec<f64> = a
+        .iter()
+        .flat_map(|&(x, _)| b.iter().map(move |&(y, _)| ground.row(x)[y] as f64))
+        .collect();
+    let cost = |i: usize, j: usize| cost[i * q + j];
+
+    let mut supply: Vec<f64> = a.iter().map(|&(_, x)| x as f64).collect();
+    let mut demand: Vec<f64> = b.iter().map(|&(_, y)| y as f64).collect();
+    let mut flow = vec![0.0; p * q];
+    let (mut from, mut to, mut sink) = (vec![0.0; p], vec![0.0; q], 0.0);
+
+    let total = f64::min(supply.iter().sum(), demand.iter().sum());
+    let eps = total * 1e-9;
+
+    // every edge into a bin of `b` leaves a bin of `a`, so dijkstra only has to settle the p
+    // bins of `a` and can keep the q distances into `b` in a flat array. `back[j]` lists the
+    // bins of `a` that already send to j, which is where the residual graph turns back
+    let mut back = vec![Vec::new(); q];
+
+    let mut d = 0.0;
+    let mut moved = 0.0;
+    while total - moved > eps {
+        let mut da: Vec<f64> = supply
+            .iter()
+            .map(|&x| if x > eps { 0.0 } else { f64::INFINITY })
+            .collect();
+        let mut db = vec![f64::INFINITY; q];
+        let mut done = vec![false; p];
+        let mut via = vec![None; p];
+        let mut prev = vec![0; q];
+        let (mut ds, mut last) = (f64::INFINITY, 0);
+
+        while let Some(i) = (0..p)
+            .filter(|&i| !done[i] && da[i] < ds)
+            .min_by(|&x, &y| da[x].total_cmp(&da[y]))
+        {
+            done[i] = true;
+            for j in 0..q {
+                let x = da[i] + (cost(i, j) + from[i] - to[j]).max(0.0);
+                if x >= db[j] {
+                    continue;
+                }
+                db[j] = x;
+                prev[j] = i;
+                if demand[j] > eps && x + (to[j] - sink).max(0.0) < ds {
+                    ds = x + (to[j] - sink).max(0.0);
+                    last = j;
+                }
+                for &k in &back[j] {
+                    let y = x + (to[j] - cost(k, j) - from[k]).max(0.0);
+                    if !done[k] && y < da[k] {
+                        da[k] = y;
+                        via[k] = Some(j);
+                    }
+                }
+            }
+        }
+        if !ds.is_finite() {
+            break;
+        }
+        // anything further than the sink is capped, which keeps the potentials feasible
+        for (h, &x) in from.iter_mut().zip(&da) {
+            *h += x.min(ds);
+        }
+        for (h, &x) in to.iter_mut().zip(&db) {
+            *h += x.min(ds);
+        }
+        sink += ds;
+
+        let mut path = Vec::new();
+        let mut j = last;
+        let first = loop {
+            let i = prev[j];
+            path.push((i, j, true));
+            match via[i] {
+                Some(k) => {
+                    path.push((i, k, false));
+                    j = k;
+                }
+                None => break i,
+            }
+        };
+
+        let amount = path
+            .iter()
+            .filter(|&&(_, _, forward)| !forward)
+            .map(|&(i, j, _)| flow[i * q + j])
+            .fold(demand[last].min(supply[first]), f64::min);
+
+        for &(i, j, forward) in &path {
+            let x = if forward { amount } else { -amount };
+            if forward && flow[i * q + j] <= eps {
+                back[j].push(i);
+            }
+            flow[i * q + j] += x;
+            if !forward && flow[i * q + j] <= eps {
+                back[j].retain(|&k| k != i);
+            }
+            d += x * cost(i, j);
+        }
+        supply[first] -= amount;
+        demand[last] -= amount;
+        moved += amount;
+    }
+
+    d as f32
+}
+
+pub fn emd_ground(a: &Sparse, b: &Sparse, ground: &Matrix) -> f32 {
+    let a: Vec<_> = a.pairs().collect();
+    let b: Vec<_> = b.pairs().collect();
+
+    transport(&a, &b, ground)
+}
+
+pub fn emd_ground_dense<T: Bin>(a: &Sparse, b: &[T], ground: &Matrix) -> f32 {
+    let a: Vec<_> = a.pairs().collect();
+    let b: Vec<_> = b
+        .iter()
+        .enumerate()
+        .map(|(j, y)| (j, y.to_f32()))
+        .filter(|&(_, y)| y > 0.0)
+        .collect();
+
+    transport(&a, &b, ground)
+}
+
+fn squared(pairs: impl Iterator<Item = (usize, f32, f32)>) -> f32 {
+    pairs.map(|(_, x, y)| (x - y) * (x - y)).sum()
+}
+
+pub fn mse(a: &Sparse, b: &Sparse) -> f32 {
+    squared(merge(a, b))
+}
+
+pub fn mse_dense<T: Bin>(a: &Sparse, b: &[T]) -> f32 {
+    squared(dense(a, b))
+}
+
+fn angle(pairs: impl Iterator<Item = (usize, f32, f32)>) -> f32 {
+    let (mut xy, mut xx, mut yy) = (0.0, 0.0, 0.0);
+    for (_, x, y) in pairs {
+        xy += x * y;
+        xx += x * x;
+        yy += y * y;
+    }
+
+    if xx == 0.0 && yy == 0.0 {
+        0.0
+    } else if xx == 0.0 || yy == 0.0 {
+        1.0
+    } else {
+        1.0 - xy / (xx.sqrt() * yy.sqrt())
+    }
+}
+
+pub fn cosine(a: &Sparse, b: &Sparse) -> f32 {
+    angle(merge(a, b))
+}
+
+pub fn cosine_dense<T: Bin>(a: &Sparse, b: &[T]) -> f32 {
+    angle(dense(a, b))
+}
+
+pub fn agg(input: Option<Histogram>, other: &Sparse) -> Option<Histogram> {
+    let mut h = input.unwrap_or_else(|| Histogram::new(other.n));
+    for (i, x) in other.pairs() {
+        h.x[i] += x;
+    }
+
+    Some(h)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::*;
+
+    use super::*;
+    use crate::histogram::{self, Metric};
+
+    #[test]
+    fn test_sparse_matches_dense() {
+        let a = Sparse::from_pairs(7, vec![(3, 0.25), (0, 0.5), (3, 0.25)]);
+        let b = Sparse::from_pairs(7, vec![(1, 0.75), (6, 0.25)]);
+
+        let (x, y) = (a.to_dense(), b.to_dense());
+
+        assert_eq!(a.i, vec![0, 3]);
+        assert_eq!(a.get(3), 0.5);
+
+        assert_eq!(emd(&a, &b), histogram::emd(&x, &y));
+        assert_eq!(emd_dense(&a, &y.x), histogram::emd(&x, &y));
+        assert_eq!(mse(&a, &b), histogram::mse(&x, &y));
+        assert_eq!(mse_dense(&a, &y.x), mse(&a, &b));
+        assert_eq!(cosine(&a, &b), cosine_dense(&a, &y.x));
+        assert!(cosine(&a, &a).abs() < 1e-6);
+
+        let mut ground = Matrix::new(0, 7);
+        for i in 0..7 {
+            ground.push(
+                &(0..7)
+                    .map(|j| (i as f32 - j as f32).abs())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        assert_eq!(emd_ground(&a, &b, &ground), 1.75);
+        assert_eq!(emd_ground_dense(&a, &y.x, &ground), 1.75);
+        assert_eq!(Metric::Emd.distance(&x, &y), 1.75);
+
+        let c = agg(agg(None, &a), &b).unwrap();
+        assert_eq!(c.x, vec![0.5, 0.75, 0.0, 0.5, 0.0, 0.0, 0.25]);
+    }
+
+    #[test]
+    fn test_ground_emd_is_optimal() {
+        let mut ground = Matrix::new(0, 8);
+        for i in 0..8 {
+            ground.push(
+                &(0..8)
+                    .map(|j| (i as f32 - j as f32).abs())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // matching each bin to its nearest free target moves 2 -> 3 and then 3 -> 0 for 4,
+        // while 2 -> 0 and 3 -> 3 costs 2
+        let a = Sparse::from_pairs(8, vec![(2, 1.0), (3, 1.0)]);
+        let b = Sparse::from_pairs(8, vec![(0, 1.0), (3, 1.0)]);
+        assert!((emd_ground(&a, &b, &ground) - 2.0).abs() < 1e-6);
+
+        // only the smaller mass moves
+        let a = Sparse::from_pairs(8, vec![(0, 1.0)]);
+        let b = Sparse::from_pairs(8, vec![(0, 0.5), (2, 1.0)]);
+        assert!((emd_ground(&a, &b, &ground) - 1.0).abs() < 1e-6);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut random = || {
+                let mut x: Vec<f32> = (0..8)
+                    .map(|_| rng.gen_range(0.0..1.0f32).max(0.5) - 0.5)
+                    .collect();
+                x[rng.gen_range(0..8)] += 0.25;
+                Histogram::from(x).norm()
+            };
+            let (x, y) = (random(), random());
+
+            let expected = histogram::emd(&x, &y);
+            let a = Sparse::from_dense(&x);
+            assert!((emd_ground(&a, &Sparse::from_dense(&y), &ground) - expected).abs() < 1e-4);
+            assert!((emd_ground_dense(&a, &y.x, &ground) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_ground_emd_against_a_dense_centroid() {
+        // a sparse point against a dense centroid, which is what k-means compares every point
+        // with, at a next-street cluster count
+        let n = 800;
+        let mut ground = Matrix::new(0, n);
+        for i in 0..n {
+            ground.push(
+                &(0..n)
+                    .map(|j| (i as f32 - j as f32).abs() / n as f32)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut x = vec![0.0; n];
+        for _ in 0..60 {
+            x[rng.gen_range(0..n)] += rng.gen_range(0.1..1.0f32);
+        }
+        let x = Histogram::from(x).norm();
+        let y = Histogram::from(
+            (0..n)
+                .map(|_| rng.gen_range(0.1..1.0f32))
+                .collect::<Vec<_>>(),
+        )
+        .norm();
+
+        let expected = histogram::emd(&x, &y) / n as f32;
+        let d = emd_ground_dense(&Sparse::from_dense(&x), &y.x, &ground);
+        assert!((d - expected).abs() < 1e-3, "{} vs {}", d, expected);
+    }
+}
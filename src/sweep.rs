@@ -0,0 +1,302 @@
+use rand::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::Chunks;
+use crate::error::Result;
+use crate::histogram::{Bin, Histogram, Metric};
+use crate::inspect::Street;
+use crate::k_means::{extend_centers, generate_centers, k_means_warm};
+use crate::matrix::Matrix;
+use crate::metrics::{evaluate, Report};
+use crate::pipeline::Pipeline;
+use crate::tables::{centroids, load};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Step {
+    pub k: usize,
+    pub inertia: f64,
+    pub gap: Option<(f64, f64)>,
+    pub report: Report,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sweep {
+    pub steps: Vec<Step>,
+    pub elbow: Option<usize>,
+    pub gap: Option<usize>,
+}
+
+fn within<T: Bin>(k: usize, idxs: &[usize], points: &Matrix<T>, metric: Metric) -> f64 {
+    let centers = centroids(k, idxs, points);
+
+    idxs.iter()
+        .zip(points.rows())
+        .map(|(&p, h)| metric.kernel(h, centers.row(p)) as f64)
+        .sum::<f64>()
+        / points.n.max(1) as f64
+}
+
+fn reference<R: Rng>(points: &Matrix, rng: &mut R) -> Matrix {
+    let mut lo = vec![f32::MAX; points.m];
+    let mut hi = vec![f32::MIN; points.m];
+    for h in points.rows() {
+        for (j, &x) in h.iter().enumerate() {
+            lo[j] = lo[j].min(x);
+            hi[j] = hi[j].max(x);
+        }
+    }
+
+    let mut result = Matrix::new(0, points.m);
+    for _ in 0..points.n {
+        let h: Vec<f32> = (0..points.m)
+            .map(|j| lo[j] + rng.gen::<f32>() * (hi[j] - lo[j]))
+            .collect();
+        result.push(&h);
+    }
+
+    result
+}
+
+// the k whose (k, inertia) point is furthest below the chord between the first and last step
+pub fn elbow(steps: &[Step]) -> Option<usize> {
+    let (first, last) = (steps.first()?, steps.last()?);
+
+    let dk = (last.k - first.k) as f64;
+    let di = first.inertia - last.inertia;
+    if dk == 0.0 || di <= 0.0 {
+        return None;
+    }
+
+    steps
+        .iter()
+        .map(|s| {
+            let x = (s.k - first.k) as f64 / dk;
+            let y = (first.inertia - s.inertia) / di;
+            (s.k, y - x)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(k, _)| k)
+}
+
+// the smallest k with gap(k) >= gap(k') - s(k') for the next k' in the sweep
+pub fn gap(steps: &[Step]) -> Option<usize> {
+    steps
+        .windows(2)
+        .find(|w| match (w[0].gap, w[1].gap) {
+            (Some((g, _)), Some((h, s))) => g >= h - s,
+            _ => false,
+        })
+        .map(|w| w[0].k)
+}
+
+pub fn sweep<T: Bin>(
+    ks: &[usize],
+    points: &Matrix<T>,
+    metric: Metric,
+    references: usize,
+    weight: impl Fn(usize) -> f64,
+    equity: impl Fn(&Histogram) -> f32,
+) -> Result<Sweep> {
+    let mut ks = ks.to_vec();
+    ks.sort_unstable();
+    ks.dedup();
+
+    let mut rng = thread_rng();
+
+    let floats = points.convert::<f32>();
+    let references: Vec<Matrix> = (0..references)
+        .map(|_| reference(&floats, &mut rng))
+        .collect();
+    drop(floats);
+
+    let mut warm: Option<Matrix> = None;
+    let mut cold: Vec<Option<Matrix>> = vec![None; references.len()];
+
+    let mut steps = Vec::new();
+    for &k in &ks {
+        println!("sweeping k = {}", k);
+
+        let start = match warm.take() {
+            Some(centers) => extend_centers(k, centers, points, metric, &mut rng),
+            None => generate_centers(k, points, metric, &mut rng),
+        };
+
+        let idxs = k_means_warm(&start, points, metric);
+
+        let inertia = within(k, &idxs, points, metric);
+        let report = evaluate(k, &idxs, points, metric, &weight, &equity)?;
+
+        warm = Some(centroids(k, &idxs, points));
+
+        let gap = if references.is_empty() {
+            None
+        } else {
+            let logs: Vec<f64> = references
+                .iter()
+                .zip(cold.iter_mut())
+                .map(|(r, c)| {
+                    let start = match c.take() {
+                        Some(centers) => extend_centers(k, centers, r, metric, &mut rng),
+                        None => generate_centers(k, r, metric, &mut rng),
+                    };
+
+                    let idxs = k_means_warm(&start, r, metric);
+                    *c = Some(centroids(k, &idxs, r));
+
+                    within(k, &idxs, r, metric).max(f64::MIN_POSITIVE).ln()
+                })
+                .collect();
+
+            let b = logs.len() as f64;
+            let mean = logs.iter().sum::<f64>() / b;
+            let sd = (logs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / b).sqrt();
+
+            Some((
+                mean - inertia.max(f64::MIN_POSITIVE).ln(),
+                sd * (1.0 + 1.0 / b).sqrt(),
+            ))
+        };
+
+        println!("k = {}: inertia = {}, gap = {:?}", k, inertia, gap);
+
+        steps.push(Step {
+            k,
+            inertia,
+            gap,
+            report,
+        });
+    }
+
+    Ok(Sweep {
+        elbow: elbow(&steps),
+        gap: gap(&steps),
+        steps,
+    })
+}
+
+pub fn sample<R: Rng>(
+    pipeline: &Pipeline,
+    street: Street,
+    count: usize,
+    rng: &mut R,
+) -> Result<(Vec<usize>, Matrix)> {
+    let path = pipeline.histograms.clone() + street.name();
+
+    let mut idxs = Vec::new();
+    let mut points = Matrix::new(0, 0);
+
+    let mut take = |n: usize, i: usize, row: &[f32], rng: &mut R| {
+        if rng.gen_bool((count as f64 / n as f64).min(1.0)) {
+            points.m = row.len();
+            points.push(row);
+            idxs.push(i);
+        }
+    };
+
+    match street {
+        Street::Flop => {
+            let rows: Vec<Vec<u16>> = load(&(path + ".bin"))?;
+            for (i, row) in rows.iter().enumerate() {
+                let row: Vec<f32> = row.iter().map(|&x| x as f32).collect();
+                take(rows.len(), i, &row, rng);
+            }
+        }
+        Street::Turn => {
+            let rows: Vec<Vec<u8>> = load(&(path + ".bin"))?;
+            for (i, row) in rows.iter().enumerate() {
+                let row: Vec<f32> = row.iter().map(|&x| x as f32).collect();
+                take(rows.len(), i, &row, rng);
+            }
+        }
+        Street::River if Chunks::exists(&(path.clone() + "/")) => {
+            let chunks = Chunks::open(&(path + "/"))?;
+            for c in 0..chunks.count() {
+                for (i, row) in chunks.range(c).zip(chunks.load(c)?.rows()) {
+                    let row: Vec<f32> = row.iter().map(|&x| chunks.dequantise(x)).collect();
+                    take(chunks.n, i, &row, rng);
+                }
+            }
+        }
+        Street::River => {
+            let rows: Vec<Histogram> = load(&(path + ".bin"))?;
+            for (i, row) in rows.iter().enumerate() {
+                take(rows.len(), i, &row.x, rng);
+            }
+        }
+    }
+
+    Ok((idxs, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::chunks::Writer;
+    use crate::tables::{save, RIVER};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn test_sweep_finds_elbow() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mut points: Matrix = Matrix::new(0, 4);
+        for i in 0..90 {
+            let mut h = vec![0.05; 4];
+            h[i % 3] = 0.85;
+            h[3] += rng.gen::<f32>() * 0.01;
+            points.push(&h);
+        }
+
+        let result = sweep(
+            &[5, 1, 2, 3, 4],
+            &points,
+            Metric::Emd,
+            2,
+            |_| 1.0,
+            Histogram::mean,
+        )
+        .unwrap();
+
+        assert_eq!(
+            result.steps.iter().map(|s| s.k).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert!(result.steps[2].inertia < 0.01);
+        assert_eq!(result.elbow, Some(3));
+        assert!(result.steps.iter().all(|s| s.gap.is_some()));
+    }
+
+    #[test]
+    fn test_river_samples_match_across_storage() {
+        let dir = temp_dir("sweep");
+        let pipeline = Pipeline::new(String::new(), dir.clone(), dir.clone()).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let rows: Vec<Histogram> = (0..300)
+            .map(|_| Histogram::from((0..3).map(|_| rng.gen_range(0.0..RIVER)).collect()))
+            .collect();
+        save(&(dir.clone() + "river.bin"), &rows).unwrap();
+        let (idxs, exact) =
+            sample(&pipeline, Street::River, 100, &mut StdRng::seed_from_u64(3)).unwrap();
+
+        let mut writer = Writer::new(&(dir.clone() + "river/"), rows.len(), 3, 128, RIVER).unwrap();
+        for (i, row) in rows.iter().enumerate() {
+            writer.push(i, &row.x).unwrap();
+        }
+        writer.finish().unwrap();
+        let (chunked_idxs, chunked) =
+            sample(&pipeline, Street::River, 100, &mut StdRng::seed_from_u64(3)).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // both come back on the 0 to 2 scale of the river features
+        assert_eq!(idxs, chunked_idxs);
+        for (a, b) in exact.rows().zip(chunked.rows()) {
+            for (x, y) in a.iter().zip(b) {
+                assert!((x - y).abs() <= RIVER / 510.0 + 1e-6);
+            }
+        }
+    }
+}
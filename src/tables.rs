@@ -1,435 +1,1381 @@
 use std::{
-    io::{Read, Write},
+    fs::File,
+    io::{BufWriter, Read},
     path::Path,
-    rc::Rc,
+    sync::Arc,
 };
 
 use rayon::prelude::*;
 
 use serde::{Deserialize, Serialize};
 
-use smallvec::smallvec;
+use crate::chunks::{Chunks, Writer};
+use crate::deck::{Deck, Indexing};
+use crate::error::{check, Error, Result};
+use crate::evaluator::HandEvaluator;
+use crate::histogram::{Bin, Histogram, Metric};
+use crate::k_means::{k_means, k_means_checkpointed, k_means_chunked_checkpointed};
+use crate::mapped::{self, Table};
+use crate::matrix::Matrix;
+use crate::metrics::{self, class_weights, evaluate, evaluate_chunks};
 
-use poker_evaluator::Evaluator;
-use poker_indexer::Indexer;
+pub(crate) const BUCKETS: usize = 47;
 
-use crate::histogram::{agg, emd, mse, Histogram};
-use crate::k_means::k_means;
+pub(crate) const CHUNK: usize = 1 << 22;
 
-const BUCKETS: usize = 47;
+pub(crate) const BLOCK: usize = 1 << 12;
 
-pub fn build_strengths(evaluator: &Evaluator) -> Vec<u16> {
-    let indexer = Indexer::new(vec![5, 2]);
+// river features average 2 points per opponent beaten and 1 per tie
+pub(crate) const RIVER: f32 = 2.0;
 
-    let mut strength = vec![0; indexer.count[1] as usize];
-    for i in 0..indexer.count[0] {
-        let board = indexer.unindex(i, 0)[0];
+fn pair(hole: u64) -> (usize, usize) {
+    (
+        hole.trailing_zeros() as usize,
+        63 - hole.leading_zeros() as usize,
+    )
+}
 
-        let mut list = Vec::new();
-        for a in 0..52 {
-            for b in 0..52 {
-                let hole = 1 << a | 1 << b;
-                if a < b && (hole & board) == 0 {
-                    list.push((
-                        evaluator.evaluate(board | hole),
-                        indexer.index(smallvec![board, hole]),
-                        (a, b),
-                    ));
-                }
-            }
-        }
+// boards are computed in parallel a block at a time and yielded in board order, so the
+// output matches a sequential loop while only one block of results is held in memory
+fn boards<'a, T: Send + 'a>(
+    indexer: &'a Indexing,
+    work: impl Fn(u64) -> T + Sync + 'a,
+) -> impl Iterator<Item = T> + 'a {
+    let count = indexer.count(0);
+
+    (0..count).step_by(BLOCK).flat_map(move |start| {
+        (start..(start + BLOCK as u64).min(count))
+            .into_par_iter()
+            .map(|i| work(indexer.unindex(i, 0)[0]))
+            .collect::<Vec<T>>()
+    })
+}
 
-        list.sort();
+fn board_strengths<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    mapper: &Indexing,
+    board: u64,
+) -> Vec<(usize, u16)> {
+    let mut list: Vec<_> = deck
+        .hands(2, board)
+        .into_iter()
+        .map(|hole| {
+            (
+                evaluator.evaluate(board | hole),
+                mapper.index(&[board, hole]) as usize,
+                pair(hole),
+            )
+        })
+        .collect();
 
-        let mut used = vec![0; 52];
+    list.sort();
 
-        let mut sum = 0;
-        for x in list.chunk_by(|a, b| a.0 == b.0) {
-            for &(_, _, (a, b)) in x {
-                used[a as usize] += 1;
-                used[b as usize] += 1;
-                sum += 1;
-            }
+    let mut used = [0; 52];
 
-            for &(_, index, (a, b)) in x {
-                strength[index as usize] = sum + 1 - used[a as usize] - used[b as usize];
-            }
+    let mut result = Vec::with_capacity(list.len());
 
-            for &(_, _, (a, b)) in x {
-                used[a as usize] += 1;
-                used[b as usize] += 1;
-                sum += 1;
-            }
+    let mut sum = 0;
+    for x in list.chunk_by(|a, b| a.0 == b.0) {
+        for &(_, _, (a, b)) in x {
+            used[a] += 1;
+            used[b] += 1;
+            sum += 1;
+        }
+
+        for &(_, index, (a, b)) in x {
+            result.push((index, sum + 1 - used[a] - used[b]));
+        }
+
+        for &(_, _, (a, b)) in x {
+            used[a] += 1;
+            used[b] += 1;
+            sum += 1;
         }
     }
 
+    result
+}
+
+pub fn build_strengths<E: HandEvaluator + ?Sized>(deck: &Deck, evaluator: &E) -> Vec<u16> {
+    let indexer = deck.indexer(vec![5, 2]);
+
+    let mut strength = vec![0; indexer.count(1) as usize];
+    for (index, s) in boards(&indexer, |board| {
+        board_strengths(deck, evaluator, &indexer, board)
+    })
+    .flatten()
+    {
+        strength[index] = s;
+    }
+
     strength
 }
 
-pub fn generate_flop_histograms(strength: &Vec<u16>) -> Vec<Vec<u16>> {
-    let mapper = Indexer::new(vec![5, 2]);
+pub(crate) fn bucket(deck: &Deck, strength: u16) -> usize {
+    (((strength as f32 / deck.strength()) * BUCKETS as f32) as usize).min(BUCKETS - 1)
+}
 
-    let indexer = Indexer::new(vec![2, 3]);
+fn flop_histogram(
+    deck: &Deck,
+    strength: &[u16],
+    mapper: &Indexing,
+    cards: u64,
+    board: u64,
+) -> Vec<u16> {
+    let mut result = vec![0; BUCKETS];
+    for next in deck.hands(2, cards | board) {
+        let i = mapper.index(&[board | next, cards]) as usize;
 
-    (0..indexer.count[1])
-        .into_iter()
-        .map(|index| {
-            let val = indexer.unindex(index, 1);
+        result[bucket(deck, strength[i])] += 1;
+    }
 
-            let cards = val[0];
-            let board = val[1];
+    result
+}
 
-            let mut result = vec![0; BUCKETS];
-            for a in 0..52 {
-                for b in 0..52 {
-                    let next = 1 << a | 1 << b;
-                    if a < b && next & (cards | board) == 0 {
-                        let i = mapper.index(smallvec![board | next, cards]) as usize;
+pub fn generate_flop_histograms(deck: &Deck, strength: &[u16]) -> Vec<Vec<u16>> {
+    let mapper = deck.indexer(vec![5, 2]);
 
-                        let b = ((strength[i] as f32 / 2162.0) * BUCKETS as f32) as usize;
+    let indexer = deck.indexer(vec![2, 3]);
 
-                        result[b] += 1;
-                    }
-                }
-            }
+    (0..indexer.count(1))
+        .into_par_iter()
+        .map(|index| {
+            let val = indexer.unindex(index, 1);
 
-            result
+            flop_histogram(deck, strength, &mapper, val[0], val[1])
         })
         .collect()
 }
 
-pub fn generate_turn_histograms(strength: &Vec<u16>) -> Vec<Vec<u8>> {
-    let mapper = Indexer::new(vec![5, 2]);
+fn turn_histogram(
+    deck: &Deck,
+    strength: &[u16],
+    mapper: &Indexing,
+    cards: u64,
+    board: u64,
+) -> Vec<u8> {
+    let mut result = vec![0; BUCKETS];
+    for next in deck.hands(1, cards | board) {
+        let i = mapper.index(&[board | next, cards]) as usize;
+
+        result[bucket(deck, strength[i])] += 1;
+    }
+
+    result
+}
+
+pub fn generate_turn_histograms(deck: &Deck, strength: &[u16]) -> Vec<Vec<u8>> {
+    let mapper = deck.indexer(vec![5, 2]);
 
-    let indexer = Indexer::new(vec![2, 4]);
+    let indexer = deck.indexer(vec![2, 4]);
 
-    (0..indexer.count[1])
+    (0..indexer.count(1))
         .into_par_iter()
         .map(|index| {
             let val = indexer.unindex(index, 1);
 
-            let cards = val[0];
-            let board = val[1];
+            turn_histogram(deck, strength, &mapper, val[0], val[1])
+        })
+        .collect()
+}
+
+pub fn build_ochs_histograms(deck: &Deck, strength: &[u16]) -> Vec<Histogram> {
+    let indexer = deck.indexer(vec![5, 2]);
 
-            let mut result = vec![0; BUCKETS];
-            for c in 0..52 {
-                if (1 << c) & (cards | board) == 0 {
-                    let i = mapper.index(smallvec![board | 1 << c, cards]) as usize;
+    let mapper = deck.indexer(vec![2]);
 
-                    let b = ((strength[i] as f32 / 2162.0) * BUCKETS as f32) as usize;
+    let holes = mapper.count(0) as usize;
 
-                    result[b] += 1;
+    // integer weights add up to the same totals in any order, so the split across threads
+    // cannot change the result
+    let counts = (0..indexer.count(1))
+        .into_par_iter()
+        .fold(
+            || vec![0u64; holes * BUCKETS],
+            |mut counts, i| {
+                let val = indexer.unindex(i, 1);
+
+                let cards = val[1];
+
+                // each canonical board stands for class_size raw boards next to a fixed hole
+                let x = indexer.class_size(&val);
+
+                let hole = mapper.index(&[cards]) as usize;
+                counts[hole * BUCKETS + bucket(deck, strength[i as usize])] += x;
+                counts
+            },
+        )
+        .reduce(
+            || vec![0u64; holes * BUCKETS],
+            |mut a, b| {
+                for (x, y) in a.iter_mut().zip(b) {
+                    *x += y;
                 }
-            }
+                a
+            },
+        );
 
-            result
-        })
+    counts
+        .chunks(BUCKETS)
+        .map(|c| Histogram::from(c.iter().map(|&x| x as f32).collect::<Vec<f32>>()).norm())
         .collect()
 }
 
-pub fn build_ochs_histograms(strength: &Vec<u16>) -> Vec<Histogram> {
-    let indexer = Indexer::new(vec![5, 2]);
+fn river_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+    size: usize,
+    mapper: &Indexing,
+    board: u64,
+) -> Vec<(usize, Vec<f32>)> {
+    let mut list: Vec<_> = deck
+        .hands(2, board)
+        .into_iter()
+        .map(|hole| {
+            (
+                evaluator.evaluate(board | hole),
+                mapper.index(&[hole, board]) as usize,
+                mapper.index(&[hole]) as usize,
+                pair(hole),
+            )
+        })
+        .collect();
+
+    list.sort_unstable();
+
+    let mut count = vec![0; size];
+    for &(_, _, hole, _) in &list {
+        count[ochs[hole]] += 1;
+    }
 
-    let mapper = Indexer::new(vec![2]);
+    let mut used = vec![vec![0; 52]; size];
 
-    let mut histograms = vec![Histogram::new(BUCKETS); mapper.count[0] as usize];
-    for i in 0..indexer.count[1] {
-        let val = indexer.unindex(i, 1);
+    let mut result = Vec::new();
 
-        let cards = val[1];
-        let board = val[0];
+    let mut sum = vec![0; size];
+    for x in list.chunk_by(|a, b| a.0 == b.0) {
+        for &(_, _, hole, (a, b)) in x {
+            used[ochs[hole]][a] += 1;
+            used[ochs[hole]][b] += 1;
+            sum[ochs[hole]] += 1;
+        }
 
-        let mut list: Vec<(u64, u64)> = (0..4)
-            .map(|p| {
-                (
-                    cards >> 13 * p & ((1 << 13) - 1),
-                    board >> 13 * p & ((1 << 13) - 1),
-                )
-            })
-            .collect();
+        for &(_, index, hole, (a, b)) in x {
+            let wins = (0..size)
+                .map(|k| (sum[k] + (ochs[hole] == k) as u32 - used[k][a] - used[k][b]) as f32)
+                .collect::<Vec<f32>>();
 
-        list.sort();
+            result.push((index, hole, (a, b), wins));
+        }
+
+        for &(_, _, hole, (a, b)) in x {
+            used[ochs[hole]][a] += 1;
+            used[ochs[hole]][b] += 1;
+            sum[ochs[hole]] += 1;
+        }
+    }
 
-        let mut r = 4;
-        let mut x = 1;
-        for chunk in list.chunk_by(|a, b| a == b) {
-            let c = chunk.len() as u64;
+    result.dedup_by_key(|(index, _, _, _)| *index);
 
-            for k in 0..c {
-                x *= r - k;
-                x /= k + 1;
+    result
+        .into_iter()
+        .map(|(index, hole, (a, b), mut wins)| {
+            for k in 0..size {
+                let num = count[k] + (ochs[hole] == k) as u32 - (used[k][a] + used[k][b]) / 2;
+                if num != 0 {
+                    wins[k] /= num as f32;
+                } else {
+                    wins[k] = 0.0;
+                }
             }
 
-            r -= c;
-        }
+            (index, wins)
+        })
+        .collect()
+}
 
-        histograms[mapper.index(smallvec![cards]) as usize].put(
-            ((strength[i as usize] as f32 / 2162.0) * BUCKETS as f32) as usize,
-            x as f32,
-        );
+pub fn generate_river_histograms<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+) -> Vec<Histogram> {
+    let size = ochs.iter().max().unwrap() + 1;
+
+    let mapper = deck.indexer(vec![2, 5]);
+
+    let indexer = deck.indexer(vec![5]);
+
+    let mut histograms = vec![vec![0.0; size]; mapper.count(1) as usize];
+    for (index, wins) in boards(&indexer, |board| {
+        river_histograms(deck, evaluator, ochs, size, &mapper, board)
+    })
+    .flatten()
+    {
+        histograms[index] = wins;
     }
 
-    histograms.into_iter().map(|x| x.norm()).collect()
+    histograms.into_iter().map(Histogram::from).collect()
 }
 
-pub fn generate_river_histograms(evaluator: &Evaluator, ochs: &Vec<usize>) -> Vec<Histogram> {
+pub fn generate_river_chunks<E: HandEvaluator + ?Sized>(
+    deck: &Deck,
+    evaluator: &E,
+    ochs: &[usize],
+    path: &String,
+    chunk: usize,
+) -> Result<Chunks> {
     let size = ochs.iter().max().unwrap() + 1;
 
-    let mapper = Indexer::new(vec![2, 5]);
-
-    let indexer = Indexer::new(vec![5]);
-
-    let mut histograms = vec![vec![0.0; size]; mapper.count[1] as usize];
-    for i in 0..indexer.count[0] {
-        let board = indexer.unindex(i, 0)[0];
-
-        let mut list = Vec::new();
-        for a in 0..52 {
-            for b in 0..52 {
-                let hole = 1 << a | 1 << b;
-                if a < b && (hole & board) == 0 {
-                    list.push((
-                        evaluator.evaluate(board | hole),
-                        mapper.index(smallvec![hole, board]) as usize,
-                        mapper.index(smallvec![hole]) as usize,
-                        (a, b),
-                    ));
-                }
-            }
-        }
+    let mapper = deck.indexer(vec![2, 5]);
+
+    let indexer = deck.indexer(vec![5]);
+
+    let mut writer = Writer::new(path, mapper.count(1) as usize, size, chunk, RIVER)?;
+    for (index, wins) in boards(&indexer, |board| {
+        river_histograms(deck, evaluator, ochs, size, &mapper, board)
+    })
+    .flatten()
+    {
+        writer.push(index, &wins)?;
+    }
+
+    writer.finish()
+}
 
-        list.sort_unstable();
+fn check_points<T: Bin>(points: &Matrix<T>, count: u64, m: usize) -> Result<()> {
+    check(count as usize, points.n)?;
+    check(m, points.m)
+}
+
+struct Centroids {
+    m: usize,
+    sums: Vec<f64>,
+    counts: Vec<usize>,
+    first: Vec<usize>,
+}
 
-        let mut count = vec![0; size];
-        for &(_, _, hole, _) in &list {
-            count[ochs[hole]] += 1;
+impl Centroids {
+    fn new(k: usize, m: usize) -> Self {
+        Self {
+            m,
+            sums: vec![0.0; k * m],
+            counts: vec![0; k],
+            first: vec![usize::MAX; k],
         }
+    }
 
-        let mut used = vec![vec![0; 52]; size];
+    fn add<T: Bin>(&mut self, i: usize, p: usize, row: &[T]) {
+        for (s, x) in self.sums[p * self.m..(p + 1) * self.m].iter_mut().zip(row) {
+            *s += x.to_f32() as f64;
+        }
+        self.counts[p] += 1;
+        self.first[p] = self.first[p].min(i);
+    }
 
-        let mut sum = vec![0; size];
-        for x in list.chunk_by(|a, b| a.0 == b.0) {
-            for &(_, _, hole, (a, b)) in x {
-                used[ochs[hole]][a as usize] += 1;
-                used[ochs[hole]][b as usize] += 1;
-                sum[ochs[hole]] += 1;
-            }
+    fn matrix(&self) -> Matrix {
+        let mut centers = Matrix::new(0, self.m);
+        for (p, &count) in self.counts.iter().enumerate() {
+            let n = count.max(1) as f64;
+            let sums = &self.sums[p * self.m..(p + 1) * self.m];
+            centers.push(&sums.iter().map(|&s| (s / n) as f32).collect::<Vec<_>>());
+        }
 
-            for &(_, index, hole, (a, b)) in x {
-                for k in 0..size {
-                    histograms[index][k] = (sum[k] + (ochs[hole] == k) as u32
-                        - used[k][a as usize]
-                        - used[k][b as usize]) as f32;
-                }
-            }
+        centers
+    }
 
-            for &(_, _, hole, (a, b)) in x {
-                used[ochs[hole]][a as usize] += 1;
-                used[ochs[hole]][b as usize] += 1;
-                sum[ochs[hole]] += 1;
-            }
+    // maps each cluster to its rank by centroid equity, breaking ties by the first point
+    // in the cluster so the order only depends on the partition; empty clusters go last
+    fn ranks(self, equity: impl Fn(&Histogram) -> f32) -> Vec<usize> {
+        let k = self.counts.len();
+
+        let centers = self.matrix();
+        let keys: Vec<f32> = (0..k).map(|p| equity(&centers.histogram(p))).collect();
+
+        let mut order: Vec<usize> = (0..k).collect();
+        order.sort_by(|&a, &b| {
+            (self.counts[a] == 0)
+                .cmp(&(self.counts[b] == 0))
+                .then(keys[a].total_cmp(&keys[b]))
+                .then(self.first[a].cmp(&self.first[b]))
+        });
+
+        let mut ranks = vec![0; k];
+        for (r, p) in order.into_iter().enumerate() {
+            ranks[p] = r;
         }
 
-        list.dedup_by_key(|(_, index, _, _)| index.clone());
+        ranks
+    }
+}
 
-        for (_, index, hole, (a, b)) in list {
-            for k in 0..size {
-                let num = count[k] + (ochs[hole] == k) as u32 - (used[k][a] + used[k][b]) / 2;
-                if num != 0 {
-                    histograms[index][k] /= num as f32;
-                } else {
-                    histograms[index][k] = 0.0;
-                }
-            }
+pub fn centroids<T: Bin, I: Copy + Into<usize>>(
+    k: usize,
+    idxs: &[I],
+    points: &Matrix<T>,
+) -> Matrix {
+    let mut centroids = Centroids::new(k, points.m);
+    for (i, (&p, row)) in idxs.iter().zip(points.rows()).enumerate() {
+        centroids.add(i, p.into(), row);
+    }
+
+    centroids.matrix()
+}
+
+pub fn relabel<T: Bin>(
+    k: usize,
+    idxs: &[usize],
+    points: &Matrix<T>,
+    equity: impl Fn(&Histogram) -> f32,
+) -> Vec<usize> {
+    let mut centroids = Centroids::new(k, points.m);
+    for (i, (&p, row)) in idxs.iter().zip(points.rows()).enumerate() {
+        centroids.add(i, p, row);
+    }
+
+    let ranks = centroids.ranks(equity);
+
+    idxs.iter().map(|&p| ranks[p]).collect()
+}
+
+fn accumulate_chunks(k: usize, idxs: &[u16], chunks: &Chunks) -> Result<Centroids> {
+    let mut centroids = Centroids::new(k, chunks.size);
+    for c in 0..chunks.count() {
+        let range = chunks.range(c);
+        for ((i, &p), row) in range.clone().zip(&idxs[range]).zip(chunks.load(c)?.rows()) {
+            centroids.add(i, p as usize, row);
         }
     }
 
-    histograms.into_iter().map(|x| Histogram::from(x)).collect()
+    Ok(centroids)
 }
 
-pub fn cluster_flops(count: usize, path: &String, strength: &Rc<Vec<u16>>) -> Vec<u16> {
+pub fn centroids_chunked(k: usize, idxs: &[u16], chunks: &Chunks) -> Result<Matrix> {
+    Ok(accumulate_chunks(k, idxs, chunks)?.matrix())
+}
+
+pub fn relabel_chunked(
+    k: usize,
+    idxs: &[u16],
+    chunks: &Chunks,
+    equity: impl Fn(&Histogram) -> f32,
+) -> Result<Vec<u16>> {
+    let ranks = accumulate_chunks(k, idxs, chunks)?.ranks(equity);
+
+    Ok(idxs.iter().map(|&p| ranks[p as usize] as u16).collect())
+}
+
+pub fn average_equity(h: &Histogram) -> f32 {
+    h.s() / h.n() as f32
+}
+
+// chunked rows are stored on a 0 to 255 scale
+fn quantised_equity(chunks: &Chunks) -> impl Fn(&Histogram) -> f32 + Sync {
+    let max = chunks.max;
+
+    move |h| average_equity(h) / 255.0 * max
+}
+
+pub type Source<'a, T> = &'a (dyn Fn() -> Result<Arc<T>> + Sync);
+
+pub fn cluster_flops(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    strength: Source<Table>,
+) -> Result<Vec<u16>> {
     println!("Getting Flops");
 
-    let flop: Vec<Histogram> = get(
-        &(path.clone() + "flop.bin"),
-        Box::new({
-            let input = Rc::clone(strength);
-            move || generate_flop_histograms(&input)
+    let flop = Matrix::from_rows(get(
+        path,
+        Box::new(move || {
+            let strength = strength()?;
+            Ok(generate_flop_histograms(deck, &strength))
         }),
-    )
-    .into_iter()
-    .map(|x| Histogram::from(x.into_iter().map(|x| x.into()).collect()))
-    .collect();
+    )?)?;
+
+    check_points(&flop, deck.indexer(vec![2, 3]).count(1), BUCKETS)?;
 
     println!("Clustering Flops");
 
-    k_means(count, 20, &flop, agg, emd)
+    let idxs = k_means_checkpointed(count, 20, &flop, Metric::Emd, &(path.clone() + ".ckpt"), 5)?;
+
+    Ok(relabel(count, &idxs, &flop, Histogram::mean)
         .into_iter()
         .map(|x| x as u16)
-        .collect()
+        .collect())
 }
 
-pub fn cluster_turns(count: usize, path: &String, strength: &Rc<Vec<u16>>) -> Vec<u16> {
+pub fn cluster_turns(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    strength: Source<Table>,
+) -> Result<Vec<u16>> {
     println!("Getting Turns");
 
-    let turn: Vec<Histogram> = get(
+    let turn = Matrix::from_rows(get(
         path,
-        Box::new({
-            let input = Rc::clone(strength);
-            move || generate_turn_histograms(&input)
+        Box::new(move || {
+            let strength = strength()?;
+            Ok(generate_turn_histograms(deck, &strength))
         }),
-    )
-    .into_iter()
-    .map(|x| Histogram::from(x.into_iter().map(|x| x.into()).collect()))
-    .collect();
+    )?)?;
+
+    check_points(&turn, deck.indexer(vec![2, 4]).count(1), BUCKETS)?;
 
     println!("Clustering Turns");
 
-    k_means(count, 5, &turn, agg, emd)
+    let idxs = k_means_checkpointed(count, 5, &turn, Metric::Emd, &(path.clone() + ".ckpt"), 5)?;
+
+    Ok(relabel(count, &idxs, &turn, Histogram::mean)
         .into_iter()
         .map(|x| x as u16)
-        .collect()
+        .collect())
 }
 
-pub fn cluster_ochs(count: usize, path: &String, strength: &Rc<Vec<u16>>) -> Vec<usize> {
+pub fn cluster_ochs(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    strength: Source<Table>,
+) -> Result<Vec<usize>> {
     println!("Getting OCHS");
 
     let ochs: Vec<Histogram> = get(
         path,
-        Box::new({
-            let input = Rc::clone(strength);
-            move || build_ochs_histograms(&input)
+        Box::new(move || {
+            let strength = strength()?;
+            Ok(build_ochs_histograms(deck, &strength))
         }),
-    );
+    )?;
+    let ochs = Matrix::from_rows(ochs.into_iter().map(|h| h.x).collect())?;
+
+    check_points(&ochs, deck.indexer(vec![2]).count(0), BUCKETS)?;
 
     println!("Clustering OCHS");
 
-    k_means(count, 95, &ochs, agg, emd)
+    let idxs = k_means(count, 95, &ochs, Metric::Emd);
+
+    Ok(relabel(count, &idxs, &ochs, Histogram::mean))
 }
 
 pub fn cluster_rivers(
+    deck: &Deck,
     count: usize,
     path: &String,
-    evaluator: &Rc<Evaluator>,
-    ochs: &Rc<Vec<usize>>,
-) -> Vec<u16> {
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+) -> Result<Vec<u16>> {
     println!("Getting Rivers");
 
+    let size = ochs.iter().max().map_or(0, |x| x + 1);
+
     let river: Vec<Histogram> = get(
         path,
-        Box::new({
-            let evaluator = Rc::clone(&evaluator);
-            let ochs = Rc::clone(&ochs);
-            move || generate_river_histograms(&evaluator, &ochs)
+        Box::new(move || {
+            let evaluator = evaluator()?;
+            Ok(generate_river_histograms(deck, &*evaluator, ochs))
         }),
-    );
+    )?;
+    let river = Matrix::from_rows(river.into_iter().map(|h| h.x).collect())?;
+
+    check_points(&river, deck.indexer(vec![2, 5]).count(1), size)?;
 
     println!("Clustering Rivers");
 
-    k_means(count, 1, &river, agg, mse)
+    let idxs = k_means_checkpointed(count, 1, &river, Metric::Mse, &(path.clone() + ".ckpt"), 1)?;
+
+    Ok(relabel(count, &idxs, &river, average_equity)
         .into_iter()
         .map(|x| x as u16)
-        .collect()
+        .collect())
 }
 
-pub fn get_strengths(path: String, evaluator: &Rc<Evaluator>) -> Vec<u16> {
+pub fn cluster_rivers_chunked(
+    deck: &Deck,
+    count: usize,
+    path: &String,
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+) -> Result<Vec<u16>> {
+    println!("Getting Rivers");
+
+    let size = ochs.iter().max().map_or(0, |x| x + 1);
+
+    let river = if Chunks::exists(path) {
+        Chunks::open(path)?
+    } else {
+        let evaluator = evaluator()?;
+        generate_river_chunks(deck, &*evaluator, ochs, path, CHUNK)?
+    };
+
+    cluster_river_chunks(deck, count, path, &river, size)
+}
+
+pub(crate) fn cluster_river_chunks(
+    deck: &Deck,
+    count: usize,
+    path: &str,
+    river: &Chunks,
+    size: usize,
+) -> Result<Vec<u16>> {
+    check(deck.indexer(vec![2, 5]).count(1) as usize, river.n)?;
+    check(size, river.size)?;
+
+    println!("Clustering Rivers");
+
+    let checkpoint = path.trim_end_matches('/').to_string() + ".ckpt";
+
+    let idxs = k_means_chunked_checkpointed(count, 1, river, Metric::Mse, &checkpoint, 1)?;
+
+    relabel_chunked(count, &idxs, river, quantised_equity(river))
+}
+
+pub fn get_strengths(
+    deck: &Deck,
+    path: String,
+    evaluator: Source<dyn HandEvaluator>,
+) -> Result<Table> {
     println!("Getting Strengths");
 
-    get(
+    let strength = mapped::get(
         &path,
-        Box::new({
-            let evaluator = Rc::clone(evaluator);
-            move || build_strengths(&evaluator)
+        Box::new(move || {
+            let evaluator = evaluator()?;
+            Ok(build_strengths(deck, &*evaluator))
         }),
-    )
+    )?;
+
+    check(deck.indexer(vec![5, 2]).count(1) as usize, strength.len())?;
+
+    Ok(strength)
 }
 
 pub fn get_flop_clusters(
+    deck: &Deck,
     n: usize,
     file: String,
     path: String,
-    strength: &Rc<Vec<u16>>,
-) -> Vec<u16> {
-    get(
+    strength: Source<Table>,
+) -> Result<Table> {
+    let histograms = path.clone();
+    let table = mapped::get(
         &file,
-        Box::new({
-            let strength = Rc::clone(strength);
-            move || cluster_flops(n, &path, &strength)
-        }),
-    )
+        Box::new(move || cluster_flops(deck, n, &path, strength)),
+    )?;
+
+    let indexer = deck.indexer(vec![2, 3]);
+    check(indexer.count(1) as usize, table.len())?;
+
+    metrics::write_report(&file, || {
+        let points = Matrix::from_rows(load::<Vec<Vec<u16>>>(&histograms)?)?;
+        let weight = class_weights(&indexer, 1);
+        evaluate(n, &table, &points, Metric::Emd, weight, Histogram::mean)
+    })?;
+
+    Ok(table)
 }
 
 pub fn get_turn_clusters(
+    deck: &Deck,
     n: usize,
     file: String,
     path: String,
-    strength: &Rc<Vec<u16>>,
-) -> Vec<u16> {
-    get(
+    strength: Source<Table>,
+) -> Result<Table> {
+    let histograms = path.clone();
+    let table = mapped::get(
         &file,
-        Box::new({
-            let strength = Rc::clone(strength);
-            move || cluster_turns(n, &path, &strength)
-        }),
-    )
+        Box::new(move || cluster_turns(deck, n, &path, strength)),
+    )?;
+
+    let indexer = deck.indexer(vec![2, 4]);
+    check(indexer.count(1) as usize, table.len())?;
+
+    metrics::write_report(&file, || {
+        let points = Matrix::from_rows(load::<Vec<Vec<u8>>>(&histograms)?)?;
+        let weight = class_weights(&indexer, 1);
+        evaluate(n, &table, &points, Metric::Emd, weight, Histogram::mean)
+    })?;
+
+    Ok(table)
 }
 
 pub fn get_ochs_clusters(
+    deck: &Deck,
     n: usize,
     file: String,
     path: String,
-    strength: &Rc<Vec<u16>>,
-) -> Vec<usize> {
-    get(
+    strength: Source<Table>,
+) -> Result<Vec<usize>> {
+    let histograms = path.clone();
+    let table: Vec<usize> = get(
         &file,
-        Box::new({
-            let strength = Rc::clone(strength);
-            move || cluster_ochs(n, &path, &strength)
-        }),
-    )
+        Box::new(move || cluster_ochs(deck, n, &path, strength)),
+    )?;
+
+    let indexer = deck.indexer(vec![2]);
+    check(indexer.count(0) as usize, table.len())?;
+
+    metrics::write_report(&file, || {
+        let points: Vec<Histogram> = load(&histograms)?;
+        let points = Matrix::from_rows(points.into_iter().map(|h| h.x).collect())?;
+        let weight = class_weights(&indexer, 0);
+        evaluate(n, &table, &points, Metric::Emd, weight, Histogram::mean)
+    })?;
+
+    Ok(table)
 }
 
 pub fn get_river_clusters(
+    deck: &Deck,
     n: usize,
     file: String,
     path: String,
-    evaluator: Rc<Evaluator>,
-    ochs: Rc<Vec<usize>>,
-) -> Vec<u16> {
-    get(
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+) -> Result<Table> {
+    let histograms = path.clone();
+    let table = mapped::get(
         &file,
-        Box::new(move || cluster_rivers(n, &path, &evaluator, &ochs)),
-    )
+        Box::new(move || cluster_rivers(deck, n, &path, evaluator, ochs)),
+    )?;
+
+    let indexer = deck.indexer(vec![2, 5]);
+    check(indexer.count(1) as usize, table.len())?;
+
+    metrics::write_report(&file, || {
+        let points: Vec<Histogram> = load(&histograms)?;
+        let points = Matrix::from_rows(points.into_iter().map(|h| h.x).collect())?;
+        let weight = class_weights(&indexer, 1);
+        evaluate(n, &table, &points, Metric::Mse, weight, average_equity)
+    })?;
+
+    Ok(table)
 }
 
-pub fn load<T: for<'d> Deserialize<'d>>(path: &String) -> T {
+pub fn get_river_clusters_chunked(
+    deck: &Deck,
+    n: usize,
+    file: String,
+    path: String,
+    evaluator: Source<dyn HandEvaluator>,
+    ochs: &[usize],
+) -> Result<Table> {
+    let histograms = path.clone();
+    let table = mapped::get(
+        &file,
+        Box::new(move || cluster_rivers_chunked(deck, n, &path, evaluator, ochs)),
+    )?;
+
+    let indexer = deck.indexer(vec![2, 5]);
+    check(indexer.count(1) as usize, table.len())?;
+
+    metrics::write_report(&file, || {
+        let points = Chunks::open(&histograms)?;
+        let weight = class_weights(&indexer, 1);
+        let equity = quantised_equity(&points);
+        evaluate_chunks(n, &table, &points, Metric::Mse, weight, equity)
+    })?;
+
+    Ok(table)
+}
+
+pub fn check_cards(rounds: &[u32], cards: &[u64]) -> Result<()> {
+    let mut seen = 0;
+
+    let valid = rounds.len() == cards.len()
+        && rounds.iter().zip(cards).all(|(&n, &mask)| {
+            let ok = mask >> 52 == 0 && mask & seen == 0 && mask.count_ones() == n;
+            seen |= mask;
+            ok
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidCards(cards.to_vec()))
+    }
+}
+
+pub fn lookup<T: Copy>(
+    table: &[T],
+    indexer: &Indexing,
+    rounds: &[u32],
+    cards: &[u64],
+) -> Result<T> {
+    check_cards(rounds, cards)?;
+
+    let index = indexer.index(cards) as usize;
+
+    table.get(index).copied().ok_or(Error::DimensionMismatch {
+        expected: index + 1,
+        actual: table.len(),
+    })
+}
+
+pub(crate) fn check_directory(path: &String) -> Result<()> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+            Err(Error::MissingDirectory(dir.display().to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn load<T: for<'d> Deserialize<'d>>(path: &String) -> Result<T> {
+    check_directory(path)?;
+
     let mut buffer = Vec::new();
 
-    std::fs::File::open(path)
-        .unwrap()
-        .read_to_end(&mut buffer)
-        .unwrap();
+    File::open(path)?.read_to_end(&mut buffer)?;
 
-    bincode::deserialize(&buffer).unwrap()
+    Ok(bincode::deserialize(&buffer)?)
 }
 
-pub fn save<T: Serialize>(path: &String, data: &T) {
-    let buffer = bincode::serialize(data).unwrap();
+pub fn write(path: &String, f: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    check_directory(path)?;
 
-    std::fs::File::create(path)
-        .unwrap()
-        .write_all(&buffer)
-        .unwrap();
+    let temp = path.clone() + ".tmp";
+
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    f(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+pub fn save<T: Serialize>(path: &String, data: &T) -> Result<()> {
+    write(path, |writer| Ok(bincode::serialize_into(writer, data)?))
 }
 
-pub fn get<T: for<'d> Deserialize<'d> + Serialize>(path: &String, f: Box<dyn Fn() -> T>) -> T {
+pub fn get<'a, T: for<'d> Deserialize<'d> + Serialize>(
+    path: &String,
+    f: Box<dyn Fn() -> Result<T> + Send + Sync + 'a>,
+) -> Result<T> {
     if Path::new(path).exists() {
+        println!("Loading {}", path);
+
         load(path)
     } else {
-        let data = f();
+        check_directory(path)?;
+
+        let data = f()?;
+
+        save(path, &data)?;
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::OnceLock;
+
+    use rand::prelude::*;
+
+    use poker_evaluator::Evaluator;
+    use poker_indexer::Indexer;
+
+    use super::*;
+
+    use crate::cards::parse;
+    use crate::deck::choose;
+    use crate::evaluator::Reference;
+    use crate::testing::{single_thread, temp_dir};
+
+    fn evaluator() -> Evaluator {
+        Evaluator::new("data/evaluator".to_string())
+    }
+
+    fn strengths() -> Table {
+        get_strengths(
+            &Deck::STANDARD,
+            "data/histograms/strength.tbl".to_string(),
+            &|| Ok(Arc::new(evaluator())),
+        )
+        .unwrap()
+    }
+
+    fn deal<R: Rng>(rng: &mut R, deck: &Deck, used: u64, n: usize) -> u64 {
+        let cards: Vec<usize> = deck.cards().collect();
+
+        let mut hand = 0u64;
+        while hand.count_ones() < n as u32 {
+            let c = cards[rng.gen_range(0..cards.len())];
+            if (used | hand) >> c & 1 == 0 {
+                hand |= 1 << c;
+            }
+        }
+
+        hand
+    }
+
+    fn versus<E: HandEvaluator>(evaluator: &E, board: u64, hole: u64, other: u64) -> u16 {
+        match evaluator
+            .evaluate(board | other)
+            .cmp(&evaluator.evaluate(board | hole))
+        {
+            std::cmp::Ordering::Less => 2,
+            std::cmp::Ordering::Equal => 1,
+            std::cmp::Ordering::Greater => 0,
+        }
+    }
+
+    // two points per opponent hand beaten and one per tie, counting only opponents that
+    // don't share a card with the board or our hole
+    fn naive_strength<E: HandEvaluator>(deck: &Deck, evaluator: &E, board: u64, hole: u64) -> u16 {
+        deck.hands(2, board | hole)
+            .into_iter()
+            .map(|other| versus(evaluator, board, hole, other))
+            .sum()
+    }
+
+    fn check_strengths<E: HandEvaluator>(
+        deck: &Deck,
+        evaluator: &E,
+        strength: &[u16],
+        boards: &[u64],
+    ) {
+        let mapper = deck.indexer(vec![5, 2]);
+
+        for &board in boards {
+            for hole in deck.hands(2, board) {
+                let i = mapper.index(&[board, hole]) as usize;
+
+                assert_eq!(strength[i], naive_strength(deck, evaluator, board, hole));
+            }
+        }
+    }
+
+    fn check_flop_and_turn<E: HandEvaluator, R: Rng>(
+        deck: &Deck,
+        evaluator: &E,
+        strength: &[u16],
+        rng: &mut R,
+        samples: usize,
+    ) {
+        let mapper = deck.indexer(vec![5, 2]);
+
+        for _ in 0..samples {
+            let cards = deal(rng, deck, 0, 2);
+            let flop = deal(rng, deck, cards, 3);
+
+            let mut expected = vec![0u16; BUCKETS];
+            for next in deck.hands(2, cards | flop) {
+                expected[bucket(deck, naive_strength(deck, evaluator, flop | next, cards))] += 1;
+            }
+
+            assert_eq!(
+                flop_histogram(deck, strength, &mapper, cards, flop),
+                expected
+            );
+            assert_eq!(
+                expected.iter().sum::<u16>() as u64,
+                choose(deck.size() - 5, 2)
+            );
+
+            let turn = flop | deal(rng, deck, cards | flop, 1);
+
+            let mut expected = vec![0u8; BUCKETS];
+            for next in deck.hands(1, cards | turn) {
+                expected[bucket(deck, naive_strength(deck, evaluator, turn | next, cards))] += 1;
+            }
+
+            assert_eq!(
+                turn_histogram(deck, strength, &mapper, cards, turn),
+                expected
+            );
+        }
+    }
+
+    fn check_ochs(deck: &Deck, strength: &[u16], histograms: &[Histogram], holes: &[u64]) {
+        let indexer = deck.indexer(vec![5, 2]);
+        let mapper = deck.indexer(vec![2]);
+
+        for &cards in holes {
+            let mut expected = Histogram::new(BUCKETS);
+            for board in deck.hands(5, cards) {
+                let i = indexer.index(&[board, cards]) as usize;
+                expected.put(bucket(deck, strength[i]), 1.0);
+            }
+
+            let expected = expected.norm();
+            let actual = &histograms[mapper.index(&[cards]) as usize];
+            for b in 0..BUCKETS {
+                assert!((actual.get(b) - expected.get(b)).abs() < 1e-5);
+            }
+        }
+    }
+
+    fn check_river<E: HandEvaluator>(deck: &Deck, evaluator: &E, ochs: &[usize], boards: &[u64]) {
+        let mapper = deck.indexer(vec![2, 5]);
+        let size = ochs.iter().max().unwrap() + 1;
+
+        for &board in boards {
+            let actual: HashMap<usize, Vec<f32>> =
+                river_histograms(deck, evaluator, ochs, size, &mapper, board)
+                    .into_iter()
+                    .collect();
+
+            for hole in deck.hands(2, board) {
+                let mut wins = vec![0.0; size];
+                let mut counts = vec![0.0; size];
+                for other in deck.hands(2, board | hole) {
+                    let k = ochs[mapper.index(&[other]) as usize];
+                    wins[k] += versus(evaluator, board, hole, other) as f32;
+                    counts[k] += 1.0;
+                }
+
+                let index = mapper.index(&[hole, board]) as usize;
+                for k in 0..size {
+                    let expected = if counts[k] > 0.0 {
+                        wins[k] / counts[k]
+                    } else {
+                        0.0
+                    };
+                    assert!((actual[&index][k] - expected).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    // a royal flush on board ties every hand, and the straight flush draws give holes that
+    // beat every opponent
+    fn boards<R: Rng>(rng: &mut R, count: usize) -> Vec<u64> {
+        let mut boards = vec![
+            parse("AsKsQsJsTs").unwrap(),
+            parse("2c3c4c5c9d").unwrap(),
+            parse("AhAdAc7s7d").unwrap(),
+        ];
+        boards.extend((0..count).map(|_| deal(rng, &Deck::STANDARD, 0, 5)));
+
+        boards
+    }
+
+    fn small() -> (Deck, Reference, &'static [u16]) {
+        static STRENGTH: OnceLock<Vec<u16>> = OnceLock::new();
+
+        let deck = Deck::new(5, 3).unwrap();
+        let evaluator = Reference::new(deck);
+        let strength = STRENGTH.get_or_init(|| build_strengths(&deck, &evaluator));
+
+        (deck, evaluator, strength)
+    }
+
+    // the 5x3 deck only has one straight, so a board straight ties every hand, and the club
+    // draws give holes that beat every opponent or block each other's royal flush
+    fn small_boards<R: Rng>(rng: &mut R, deck: &Deck, count: usize) -> Vec<u64> {
+        let mut boards = vec![
+            parse("AcKdQhJcTd").unwrap(),
+            parse("AcKcQcJhTh").unwrap(),
+            parse("AcAdAhKcKd").unwrap(),
+        ];
+        boards.extend((0..count).map(|_| deal(rng, deck, 0, 5)));
+
+        boards
+    }
+
+    #[test]
+    fn test_small_strengths_match_brute_force() {
+        let (deck, evaluator, strength) = small();
+
+        let mut rng = StdRng::seed_from_u64(1);
+        check_strengths(
+            &deck,
+            &evaluator,
+            strength,
+            &small_boards(&mut rng, &deck, 20),
+        );
+
+        let mapper = deck.indexer(vec![5, 2]);
+        let at = |board: &str, hole: &str| {
+            strength[mapper.index(&[parse(board).unwrap(), parse(hole).unwrap()]) as usize]
+        };
+
+        let straight = parse("AcKdQhJcTd").unwrap();
+        for hole in deck.hands(2, straight) {
+            assert_eq!(strength[mapper.index(&[straight, hole]) as usize], 28);
+        }
+
+        assert_eq!(at("AcKcQcJhTh", "JcTc"), 56);
+        assert_eq!(at("AcKcQcJhTh", "TcAd"), 28);
+        assert!(at("AcKcQcJhTh", "AdKd") < 28);
+    }
+
+    #[test]
+    fn test_small_flop_and_turn_histograms_match_brute_force() {
+        let (deck, evaluator, strength) = small();
+
+        let mut rng = StdRng::seed_from_u64(2);
+        check_flop_and_turn(&deck, &evaluator, strength, &mut rng, 10);
+    }
+
+    #[test]
+    fn test_small_ochs_histograms_match_brute_force() {
+        let (deck, _, strength) = small();
+        let histograms = build_ochs_histograms(&deck, strength);
+
+        check_ochs(&deck, strength, &histograms, &deck.hands(2, 0));
+    }
+
+    #[test]
+    fn test_small_river_histograms_match_brute_force() {
+        let (deck, evaluator, _) = small();
+        let ochs: Vec<usize> = (0..deck.indexer(vec![2]).count(0) as usize)
+            .map(|i| i * 7 % 4)
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        check_river(&deck, &evaluator, &ochs, &small_boards(&mut rng, &deck, 10));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = temp_dir("load_errors");
+
+        let missing = dir.clone() + "missing/ochs.bin";
+        assert!(matches!(
+            load::<Vec<usize>>(&missing),
+            Err(Error::MissingDirectory(_))
+        ));
+        assert!(matches!(
+            save(&missing, &vec![1usize]),
+            Err(Error::MissingDirectory(_))
+        ));
+
+        let corrupt = dir.clone() + "ochs.bin";
+        std::fs::write(&corrupt, [0xff; 5]).unwrap();
+        assert!(matches!(
+            load::<Vec<usize>>(&corrupt),
+            Err(Error::Bincode(_))
+        ));
+        assert!(matches!(
+            get::<Vec<usize>>(&corrupt, Box::new(|| Ok(vec![1]))),
+            Err(Error::Bincode(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        let deck = Deck::new(4, 3).unwrap();
+        let indexer = deck.indexer(vec![2, 3]);
+        let table: Vec<u16> = (0..indexer.count(1) as u16).collect();
+
+        let hole = parse("AcKc").unwrap();
+        let flop = parse("QcJdAd").unwrap();
+        let index = indexer.index(&[hole, flop]);
+        assert_eq!(
+            lookup(&table, &indexer, &[2, 3], &[hole, flop]).unwrap(),
+            index as u16
+        );
+
+        for cards in [
+            vec![hole, parse("AcJdAd").unwrap()],
+            vec![hole, parse("QcJd").unwrap()],
+            vec![hole],
+            vec![hole, flop | 1 << 52],
+        ] {
+            assert!(matches!(
+                lookup(&table, &indexer, &[2, 3], &cards),
+                Err(Error::InvalidCards(_))
+            ));
+        }
+
+        assert!(matches!(
+            lookup(&table[..index as usize], &indexer, &[2, 3], &[hole, flop]),
+            Err(Error::DimensionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bucket_range() {
+        let deck = Deck::STANDARD;
+
+        assert_eq!(deck.strength(), 1980.0);
+        assert_eq!(bucket(&deck, 0), 0);
+        assert_eq!(bucket(&deck, 990), BUCKETS / 2);
+        assert_eq!(bucket(&deck, 1979), BUCKETS - 1);
+        assert_eq!(bucket(&deck, 1980), BUCKETS - 1);
+        assert!((0..1980).all(|s| bucket(&deck, s) <= bucket(&deck, s + 1)));
+
+        let used: std::collections::HashSet<usize> = (0..=1980).map(|s| bucket(&deck, s)).collect();
+        assert_eq!(used.len(), BUCKETS);
+    }
+
+    #[test]
+    fn test_small_deck_pipeline() {
+        let (deck, evaluator, strength) = small();
+        assert_eq!(strength.len(), 105 * 1287);
+
+        let flop = Matrix::from_rows(generate_flop_histograms(&deck, strength)).unwrap();
+        assert_eq!(flop.n, 105 * 286);
+
+        let turn = Matrix::from_rows(generate_turn_histograms(&deck, strength)).unwrap();
+        assert_eq!(turn.n, 105 * 715);
+
+        let histograms = build_ochs_histograms(&deck, strength);
+
+        let points = Matrix::from_rows(histograms.into_iter().map(|h| h.x).collect()).unwrap();
+        let idxs = k_means(4, 10, &points, Metric::Emd);
+        let ochs = relabel(4, &idxs, &points, Histogram::mean);
+        assert!(ochs.iter().all(|&p| p < 4));
+
+        let river = generate_river_histograms(&deck, &evaluator, &ochs);
+        let river = Matrix::from_rows(river.into_iter().map(|h| h.x).collect()).unwrap();
+        assert_eq!(river.n, 105 * 1287);
+
+        let idxs = k_means(8, 1, &river, Metric::Mse);
+        let labels = relabel(8, &idxs, &river, average_equity);
+        assert!(labels.iter().all(|&p| p < 8));
+    }
+
+    #[test]
+    fn test_river_chunks_match_histograms() {
+        let path = temp_dir("river_chunks");
+
+        let deck = Deck::new(4, 3).unwrap();
+        let evaluator = Reference::new(deck);
+        let ochs: Vec<usize> = (0..deck.indexer(vec![2]).count(0) as usize)
+            .map(|i| i % 3)
+            .collect();
+
+        let chunks = generate_river_chunks(&deck, &evaluator, &ochs, &path, 500).unwrap();
+        let exact = generate_river_histograms(&deck, &evaluator, &ochs);
+        assert_eq!(chunks.n, exact.len());
+
+        let mut max = 0.0f32;
+        for c in 0..chunks.count() {
+            for (row, h) in chunks.load(c).unwrap().rows().zip(&exact[chunks.range(c)]) {
+                for (&a, &b) in row.iter().zip(&h.x) {
+                    assert!((chunks.dequantise(a) - b).abs() <= RIVER / 510.0 + 1e-6);
+                    max = max.max(b);
+                }
+            }
+        }
+        assert!(max > 1.0);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_generators_ignore_thread_count() {
+        let deck = Deck::new(4, 3).unwrap();
+        let evaluator = Reference::new(deck);
+
+        let serial = |f: &(dyn Fn() -> Vec<Vec<f32>> + Sync)| single_thread(f);
+
+        let strength = build_strengths(&deck, &evaluator);
+        assert_eq!(
+            single_thread(|| build_strengths(&deck, &evaluator)),
+            strength
+        );
+
+        let flop = || {
+            generate_flop_histograms(&deck, &strength)
+                .into_iter()
+                .map(|h| h.into_iter().map(f32::from).collect())
+                .collect()
+        };
+        assert_eq!(serial(&flop), flop());
+
+        let ochs = || {
+            build_ochs_histograms(&deck, &strength)
+                .into_iter()
+                .map(|h| h.x)
+                .collect()
+        };
+        assert_eq!(serial(&ochs), ochs());
+
+        let labels: Vec<usize> = (0..deck.indexer(vec![2]).count(0) as usize)
+            .map(|i| i % 3)
+            .collect();
+        let river = || {
+            generate_river_histograms(&deck, &evaluator, &labels)
+                .into_iter()
+                .map(|h| h.x)
+                .collect()
+        };
+        assert_eq!(serial(&river), river());
+    }
+
+    #[test]
+    #[ignore = "needs data/evaluator"]
+    fn test_strengths_match_brute_force() {
+        let deck = Deck::STANDARD;
+        let evaluator = evaluator();
+        let strength = strengths();
+
+        let mut rng = StdRng::seed_from_u64(1);
+        check_strengths(&deck, &evaluator, &strength, &boards(&mut rng, 20));
+
+        let mapper = deck.indexer(vec![5, 2]);
+
+        let royal = parse("AsKsQsJsTs").unwrap();
+        let i = mapper.index(&[royal, parse("2c2d").unwrap()]) as usize;
+        assert_eq!(strength[i], 990);
+
+        let nuts = parse("2c3c4c5c9d").unwrap();
+        let i = mapper.index(&[nuts, parse("6c7c").unwrap()]) as usize;
+        assert_eq!(strength[i], 1980);
+    }
+
+    #[test]
+    #[ignore = "needs data/evaluator"]
+    fn test_flop_and_turn_histograms_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(2);
+        check_flop_and_turn(&Deck::STANDARD, &evaluator(), &strengths(), &mut rng, 5);
+    }
+
+    #[test]
+    #[ignore = "needs data/evaluator"]
+    fn test_ochs_histograms_match_brute_force() {
+        let deck = Deck::STANDARD;
+        let strength = strengths();
+        let histograms = build_ochs_histograms(&deck, &strength);
+
+        let holes: Vec<u64> = ["AcKd", "7h7s", "2c3c"]
+            .iter()
+            .map(|s| parse(s).unwrap())
+            .collect();
+        check_ochs(&deck, &strength, &histograms, &holes);
+    }
+
+    #[test]
+    #[ignore = "needs data/evaluator"]
+    fn test_river_histograms_match_brute_force() {
+        let ochs: Vec<usize> = (0..Indexer::new(vec![2]).count[0] as usize)
+            .map(|i| i * 7 % 4)
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        check_river(&Deck::STANDARD, &evaluator(), &ochs, &boards(&mut rng, 10));
+    }
+
+    #[test]
+    fn test_relabel_by_equity() {
+        let points = Matrix::from_rows(vec![
+            vec![0.0, 0.0, 1.0],
+            vec![1.0, 0.0, 0.0],
+            vec![0.0, 1.0, 0.0],
+            vec![0.0, 0.0, 1.0],
+            vec![0.0, 1.0, 0.0],
+            vec![0.0, 1.0, 0.0],
+        ])
+        .unwrap();
 
-        save(path, &data);
+        let actual = relabel(5, &[4, 0, 1, 3, 2, 1], &points, Histogram::mean);
 
-        data
+        assert_eq!(actual, vec![3, 0, 1, 4, 2, 1]);
     }
 }
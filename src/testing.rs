@@ -0,0 +1,20 @@
+// a fresh directory for one test, named after it and the process so concurrent runs and
+// tests never share files
+pub(crate) fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir()
+        .join(format!("{}_{}/", name, std::process::id()))
+        .display()
+        .to_string();
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+// runs `f` on a pool of one thread, to check a result does not depend on the thread count
+pub(crate) fn single_thread<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap()
+        .install(f)
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::deck::{Deck, Indexing};
+use crate::error::{check, Result};
+use crate::sparse::Sparse;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Transitions {
+    pub weights: Vec<f64>,
+    pub rows: Vec<Sparse>,
+}
+
+impl Transitions {
+    pub fn get(&self, from: usize, to: usize) -> f32 {
+        self.rows[from].get(to)
+    }
+}
+
+type Counts = Vec<HashMap<u32, f64>>;
+
+fn merge(mut a: Counts, b: Counts) -> Counts {
+    for (x, y) in a.iter_mut().zip(b) {
+        for (j, w) in y {
+            *x.entry(j).or_default() += w;
+        }
+    }
+
+    a
+}
+
+// every index stands for class_size raw combos and each unseen card of the deck is equally
+// likely to come next, so summing over indices gives the raw-combo distribution
+pub fn transitions(
+    deck: &Deck,
+    from: &Indexing,
+    to: &Indexing,
+    current: &[u16],
+    next: &[u16],
+) -> Result<Transitions> {
+    check(from.count(1) as usize, current.len())?;
+    check(to.count(1) as usize, next.len())?;
+
+    let k = current.iter().map(|&x| x as usize + 1).max().unwrap_or(0);
+    let m = next.iter().map(|&x| x as usize + 1).max().unwrap_or(0);
+
+    let counts = (0..current.len())
+        .into_par_iter()
+        .fold(
+            || vec![HashMap::new(); k],
+            |mut counts: Counts, i| {
+                let rounds = from.unindex(i as u64, 1);
+                let (hole, board) = (rounds[0], rounds[1]);
+
+                let used = hole | board;
+                let w = from.class_size(&rounds) as f64
+                    / (deck.size() - used.count_ones() as usize) as f64;
+
+                let row = &mut counts[current[i] as usize];
+                for c in deck.cards() {
+                    if used >> c & 1 == 0 {
+                        let j = to.index(&[hole, board | 1 << c]) as usize;
+                        *row.entry(next[j] as u32).or_default() += w;
+                    }
+                }
+
+                counts
+            },
+        )
+        .reduce(|| vec![HashMap::new(); k], merge);
+
+    let weights = counts.iter().map(|row| row.values().sum()).collect();
+    let rows = counts
+        .into_iter()
+        .map(|row| {
+            Sparse::from_pairs(m, row.into_iter().map(|(j, w)| (j, w as f32)).collect()).norm()
+        })
+        .collect();
+
+    Ok(Transitions { weights, rows })
+}
+
+pub fn flop_to_turn(deck: &Deck, flop: &[u16], turn: &[u16]) -> Result<Transitions> {
+    transitions(
+        deck,
+        &deck.indexer(vec![2, 3]),
+        &deck.indexer(vec![2, 4]),
+        flop,
+        turn,
+    )
+}
+
+pub fn turn_to_river(deck: &Deck, turn: &[u16], river: &[u16]) -> Result<Transitions> {
+    transitions(
+        deck,
+        &deck.indexer(vec![2, 4]),
+        &deck.indexer(vec![2, 5]),
+        turn,
+        river,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_transitions_match_enumeration() {
+        let deck = Deck::new(4, 3).unwrap();
+
+        for board in [3, 4] {
+            let from = deck.indexer(vec![2, board]);
+            let to = deck.indexer(vec![2, board + 1]);
+
+            let current: Vec<u16> = (0..from.count(1)).map(|i| (i * 7 % 5) as u16).collect();
+            let next: Vec<u16> = (0..to.count(1)).map(|j| (j * 11 % 6) as u16).collect();
+
+            let t = match board {
+                3 => flop_to_turn(&deck, &current, &next).unwrap(),
+                _ => turn_to_river(&deck, &current, &next).unwrap(),
+            };
+
+            let mut counts = vec![vec![0.0; 6]; 5];
+            for hole in deck.hands(2, 0) {
+                for cards in deck.hands(board as usize, hole) {
+                    let i = from.index(&[hole, cards]) as usize;
+                    for c in deck.cards() {
+                        if (hole | cards) >> c & 1 == 0 {
+                            let j = to.index(&[hole, cards | 1 << c]) as usize;
+                            counts[current[i] as usize][next[j] as usize] += 1.0;
+                        }
+                    }
+                }
+            }
+
+            let left = (deck.size() - 2 - board as usize) as f64;
+            for (r, row) in counts.iter().enumerate() {
+                let total: f64 = row.iter().sum();
+                assert!((t.weights[r] - total / left).abs() < 1e-6);
+                assert!((t.rows[r].s() - 1.0).abs() < 1e-5);
+                for (c, &x) in row.iter().enumerate() {
+                    assert!((t.get(r, c) as f64 - x / total).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transitions_check_lengths() {
+        let deck = Deck::new(4, 3).unwrap();
+        assert!(flop_to_turn(&deck, &[0; 3], &[0; 3]).is_err());
+    }
+}